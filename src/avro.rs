@@ -0,0 +1,146 @@
+//! Decoding of Avro values in the Confluent wire format.
+//!
+//! A value starts with a zero byte and the big endian id of its schema,
+//! followed by the Avro binary encoding. The schemas are read either from a
+//! directory of `<id>.avsc` files or from a schema registry.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use apache_avro::{from_avro_datum, Schema};
+use serde_json;
+use ureq;
+use error::{Error, Result};
+
+enum SchemaSource {
+    Directory(PathBuf),
+    Registry(String)
+}
+
+/// Loads schemas by their id and caches them.
+pub struct SchemaStore {
+    source: SchemaSource,
+    schemas: HashMap<u32, Schema>
+}
+
+impl SchemaStore {
+    /// `location` is the URL of a schema registry or a directory.
+    pub fn new(location: &str) -> SchemaStore {
+        let source = if location.starts_with("http://") || location.starts_with("https://") {
+            SchemaSource::Registry(location.trim_end_matches('/').to_string())
+        } else {
+            SchemaSource::Directory(PathBuf::from(location))
+        };
+        SchemaStore {
+            source,
+            schemas: HashMap::new()
+        }
+    }
+
+    /// Decodes a value into JSON.
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<serde_json::Value> {
+        if bytes.len() < 5 || bytes[0] != 0 {
+            return Err(Error::Decode("the value is not in the Confluent wire format".to_string()));
+        }
+        let id = u32::from_be_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+        let schema = self.schema(id)?;
+        let value = from_avro_datum(schema, &mut &bytes[5..], None)
+            .map_err(|e| Error::Decode(format!("Avro schema {}: {}", id, e)))?;
+        serde_json::Value::try_from(value)
+            .map_err(|e| Error::Decode(format!("Avro schema {}: {}", id, e)))
+    }
+
+    fn schema(&mut self, id: u32) -> Result<&Schema> {
+        if !self.schemas.contains_key(&id) {
+            let text = self.load(id)?;
+            let schema = Schema::parse_str(&text)
+                .map_err(|e| Error::Decode(format!("Avro schema {} is invalid: {}", id, e)))?;
+            self.schemas.insert(id, schema);
+        }
+        Ok(&self.schemas[&id])
+    }
+
+    /// Returns the JSON text of a schema.
+    fn load(&self, id: u32) -> Result<String> {
+        let not_loaded = |reason: String| Error::Decode(format!("Avro schema {} could not be loaded: {}", id, reason));
+        match self.source {
+            SchemaSource::Directory(ref dir) => {
+                let path = dir.join(format!("{}.avsc", id));
+                let mut text = String::new();
+                File::open(&path)
+                    .and_then(|mut file| file.read_to_string(&mut text))
+                    .map_err(|e| not_loaded(format!("{}: {}", path.display(), e)))?;
+                Ok(text)
+            }
+            SchemaSource::Registry(ref url) => {
+                let body = ureq::get(&format!("{}/schemas/ids/{}", url, id))
+                    .call()
+                    .map_err(|e| not_loaded(e.to_string()))?
+                    .body_mut()
+                    .read_to_string()
+                    .map_err(|e| not_loaded(e.to_string()))?;
+                let response: serde_json::Value = serde_json::from_str(&body).map_err(|e| not_loaded(e.to_string()))?;
+                match response["schema"].as_str() {
+                    Some(schema) => Ok(schema.to_string()),
+                    None => Err(not_loaded("the response has no schema".to_string()))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::write;
+    use apache_avro::{Schema, to_avro_datum};
+    use apache_avro::types::Value;
+    use tempfile::TempDir;
+    use error::Error;
+    use super::SchemaStore;
+
+    const SCHEMA: &str = r#"{"type": "record", "name": "Order", "fields": [
+        {"name": "id", "type": "long"},
+        {"name": "status", "type": "string"}
+    ]}"#;
+
+    /// A schema directory, which stands in for a schema registry, with the
+    /// schema 7.
+    fn schema_dir() -> TempDir {
+        let dir = TempDir::new().unwrap();
+        write(dir.path().join("7.avsc"), SCHEMA).unwrap();
+        dir
+    }
+
+    fn decode_error(store: &mut SchemaStore, bytes: &[u8]) -> String {
+        match store.decode(bytes) {
+            Err(Error::Decode(msg)) => msg,
+            result => panic!("{:?}", result)
+        }
+    }
+
+    #[test]
+    fn decode() {
+        let dir = schema_dir();
+        let mut store = SchemaStore::new(dir.path().to_str().unwrap());
+        let schema = Schema::parse_str(SCHEMA).unwrap();
+        let record = Value::Record(vec![
+            ("id".to_string(), Value::Long(42)),
+            ("status".to_string(), Value::String("new".to_string()))
+        ]);
+        let mut bytes = vec![0, 0, 0, 0, 7];
+        bytes.extend(to_avro_datum(&schema, record).unwrap());
+        assert_eq!(store.decode(&bytes).unwrap(), json!({"id": 42, "status": "new"}));
+    }
+
+    #[test]
+    fn invalid_frames() {
+        let dir = schema_dir();
+        let mut store = SchemaStore::new(dir.path().to_str().unwrap());
+        assert!(decode_error(&mut store, &[1, 0, 0, 0, 7, 2]).contains("not in the Confluent wire format"));
+        assert!(decode_error(&mut store, &[0, 0, 0, 7]).contains("not in the Confluent wire format"));
+        let msg = decode_error(&mut store, &[0, 0, 0, 0, 8, 2]);
+        assert!(msg.starts_with("Avro schema 8 could not be loaded"), "{}", msg);
+    }
+}
@@ -0,0 +1,384 @@
+//! Table columns extracted from JSON values.
+
+use serde_json::Value;
+use value::{SqlType, SqlValue};
+
+/// The names of the columns every table has. JSON Lines dumps also have
+/// `headers`.
+const RESERVED_NAMES: [&str; 7] = ["partition", "offset", "key", "value", "timestamp", "timestamp_type", "headers"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColumnType {
+    Text,
+    Integer,
+    Real,
+    /// The JSON text of the value.
+    Json
+}
+
+/// A step of a JSON path.
+#[derive(Clone, Debug, PartialEq)]
+enum Step {
+    Field(String),
+    Index(usize)
+}
+
+/// A column whose content is taken from a JSON path in the message value.
+#[derive(Clone, Debug)]
+pub struct Column {
+    pub name: String,
+    path: Vec<Step>,
+    pub column_type: ColumnType
+}
+
+impl ColumnType {
+    pub fn parse(s: &str) -> Result<ColumnType, String> {
+        match s {
+            "text" => Ok(ColumnType::Text),
+            "integer" => Ok(ColumnType::Integer),
+            "real" => Ok(ColumnType::Real),
+            "json" => Ok(ColumnType::Json),
+            _ => Err(format!("Unknown column type '{}'. Use 'text', 'integer', 'real' or 'json'", s))
+        }
+    }
+
+    /// The type of the column.
+    pub fn sql_type(&self) -> SqlType {
+        match *self {
+            ColumnType::Text => SqlType::Text,
+            ColumnType::Integer => SqlType::Integer,
+            ColumnType::Real => SqlType::Real,
+            ColumnType::Json => SqlType::Json
+        }
+    }
+}
+
+impl Column {
+    /// Parses `name=$.path.to.field:type`. The type is optional and defaults
+    /// to `text`.
+    pub fn parse(s: &str) -> Result<Column, String> {
+        let mut parts = s.splitn(2, '=');
+        let name = parts.next().unwrap();
+        let rest = parts.next().ok_or_else(|| format!("'{}' is not a column like 'name=$.path:type'", s))?;
+        let type_start = rest.rfind(':').filter(|&colon| rest.rfind(']').is_none_or(|b| colon > b));
+        let (path, column_type) = match type_start {
+            Some(colon) => (&rest[..colon], ColumnType::parse(&rest[colon + 1..])?),
+            None => (rest, ColumnType::Text)
+        };
+        Column::new(name, path, column_type)
+    }
+
+    pub fn new(name: &str, path: &str, column_type: ColumnType) -> Result<Column, String> {
+        check_name(name)?;
+        Ok(Column {
+            name: name.to_string(),
+            path: parse_path(path)?,
+            column_type
+        })
+    }
+
+    /// Returns the content of the column for a message value.
+    pub fn extract(&self, value: &Value) -> SqlValue {
+        let mut current = value;
+        for step in &self.path {
+            let next = match *step {
+                Step::Field(ref name) => current.get(name),
+                Step::Index(i) => current.get(i)
+            };
+            current = match next {
+                Some(v) => v,
+                None => return SqlValue::Null
+            };
+        }
+        convert(current, self.column_type)
+    }
+}
+
+/// Fails, when two columns have the same name. Like the databases, the case
+/// of the names is ignored.
+pub fn check_columns(columns: &[Column]) -> Result<(), String> {
+    let mut names: Vec<String> = vec![];
+    for column in columns {
+        let name = column.name.to_lowercase();
+        if names.contains(&name) {
+            return Err(format!("The column '{}' is given more than once", column.name));
+        }
+        names.push(name);
+    }
+    Ok(())
+}
+
+/// Infers columns from the top level fields of sampled JSON objects. The
+/// names of the `existing` columns are not reused.
+pub fn infer_columns(samples: &[Value], existing: &[Column]) -> Vec<Column> {
+    let mut fields: Vec<(String, Option<ColumnType>)> = vec![];
+    for sample in samples {
+        if let Value::Object(ref map) = *sample {
+            for (field, value) in map {
+                let observed = observed_type(value);
+                match fields.iter().position(|(f, _)| f == field) {
+                    Some(i) => fields[i].1 = merge_types(fields[i].1, observed),
+                    None => fields.push((field.clone(), observed))
+                }
+            }
+        }
+    }
+    let mut taken: Vec<String> = existing.iter().map(|c| c.name.to_lowercase()).collect();
+    let mut columns = vec![];
+    for (field, column_type) in fields {
+        let name = column_name(&field, &taken);
+        taken.push(name.to_lowercase());
+        columns.push(Column {
+            name,
+            path: vec![Step::Field(field)],
+            column_type: column_type.unwrap_or(ColumnType::Text)
+        });
+    }
+    columns
+}
+
+fn observed_type(value: &Value) -> Option<ColumnType> {
+    match *value {
+        Value::Null => None,
+        Value::Bool(_) => Some(ColumnType::Integer),
+        Value::Number(ref n) if n.is_f64() => Some(ColumnType::Real),
+        Value::Number(_) => Some(ColumnType::Integer),
+        Value::String(_) => Some(ColumnType::Text),
+        Value::Array(_) | Value::Object(_) => Some(ColumnType::Json)
+    }
+}
+
+/// Returns a type which can hold the values of both types.
+fn merge_types(a: Option<ColumnType>, b: Option<ColumnType>) -> Option<ColumnType> {
+    match (a, b) {
+        (None, t) | (t, None) => t,
+        (Some(a), Some(b)) if a == b => Some(a),
+        (Some(ColumnType::Integer), Some(ColumnType::Real)) |
+        (Some(ColumnType::Real), Some(ColumnType::Integer)) => Some(ColumnType::Real),
+        (Some(ColumnType::Json), _) | (_, Some(ColumnType::Json)) => Some(ColumnType::Json),
+        _ => Some(ColumnType::Text)
+    }
+}
+
+/// Turns a JSON field name into a valid column name, which is neither
+/// reserved nor `taken`.
+fn column_name(field: &str, taken: &[String]) -> String {
+    let mut name: String = field.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    if name.chars().next().is_none_or(|c| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    while RESERVED_NAMES.contains(&name.to_lowercase().as_str()) || taken.contains(&name.to_lowercase()) {
+        name.push('_');
+    }
+    name
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    let mut chars = name.chars();
+    let valid = match chars.next() {
+        Some(c) => (c.is_ascii_alphabetic() || c == '_') && chars.all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        None => false
+    };
+    if !valid {
+        return Err(format!("'{}' is not a valid column name", name));
+    }
+    if RESERVED_NAMES.contains(&name.to_lowercase().as_str()) {
+        return Err(format!("The column name '{}' is reserved", name));
+    }
+    Ok(())
+}
+
+/// Parses a JSON path like `$.a.b[3]['c d']`.
+fn parse_path(s: &str) -> Result<Vec<Step>, String> {
+    let invalid = |reason: &str| format!("Invalid JSON path '{}': {}", s, reason);
+    if !s.starts_with('$') {
+        return Err(invalid("it must start with '$'"));
+    }
+    let chars: Vec<char> = s[1..].chars().collect();
+    let mut steps = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '.' => {
+                let start = i + 1;
+                i = start;
+                while i < chars.len() && chars[i] != '.' && chars[i] != '[' {
+                    i += 1;
+                }
+                if i == start {
+                    return Err(invalid("empty field name"));
+                }
+                steps.push(Step::Field(chars[start..i].iter().collect()));
+            }
+            '[' => {
+                let start = i + 1;
+                let quote = chars.get(start).cloned().filter(|&c| c == '\'' || c == '"');
+                let end = match quote {
+                    Some(q) => chars[start + 1..].iter().position(|&c| c == q).map(|p| start + 1 + p + 1),
+                    None => chars[start..].iter().position(|&c| c == ']').map(|p| start + p)
+                };
+                let end = match end {
+                    Some(end) if chars.get(end) == Some(&']') => end,
+                    _ => return Err(invalid("unclosed '['"))
+                };
+                let inner: String = chars[start..end].iter().collect();
+                steps.push(match quote {
+                    Some(_) => Step::Field(inner[1..inner.len() - 1].to_string()),
+                    None => Step::Index(inner.parse().map_err(|_| invalid("the index is not a number"))?)
+                });
+                i = end + 1;
+            }
+            c => return Err(invalid(&format!("unexpected '{}'", c)))
+        }
+    }
+    Ok(steps)
+}
+
+/// Converts a JSON value into the column type. Values which cannot be
+/// converted become NULL.
+fn convert(value: &Value, column_type: ColumnType) -> SqlValue {
+    match (column_type, value) {
+        (_, &Value::Null) => SqlValue::Null,
+        (ColumnType::Json, v) => SqlValue::Text(v.to_string()),
+        (ColumnType::Text, Value::String(s)) => SqlValue::Text(s.clone()),
+        (ColumnType::Text, v) => SqlValue::Text(v.to_string()),
+        (ColumnType::Integer, Value::Number(n)) => match n.as_i64() {
+            Some(i) => SqlValue::Integer(i),
+            None => n.as_f64()
+                .filter(|f| f.fract() == 0.0 && f.abs() < 9.2e18)
+                .map_or(SqlValue::Null, |f| SqlValue::Integer(f as i64))
+        },
+        (ColumnType::Integer, &Value::Bool(b)) => SqlValue::Integer(b as i64),
+        (ColumnType::Integer, Value::String(s)) => s.trim().parse().map(SqlValue::Integer).unwrap_or(SqlValue::Null),
+        (ColumnType::Real, Value::Number(n)) => n.as_f64().map_or(SqlValue::Null, SqlValue::Real),
+        (ColumnType::Real, Value::String(s)) => s.trim().parse().map(SqlValue::Real).unwrap_or(SqlValue::Null),
+        _ => SqlValue::Null
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use value::SqlValue;
+    use super::{Column, ColumnType, Step, check_columns, infer_columns, merge_types, parse_path};
+
+    fn field(name: &str) -> Step {
+        Step::Field(name.to_string())
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(parse_path("$").unwrap(), vec![]);
+        assert_eq!(parse_path("$.order.items[3]['sku id'][\"a.b\"]").unwrap(),
+                   vec![field("order"), field("items"), Step::Index(3), field("sku id"), field("a.b")]);
+    }
+
+    #[test]
+    fn invalid_paths() {
+        for &(path, reason) in &[("order.status", "it must start with '$'"),
+                                 ("$.", "empty field name"),
+                                 ("$..a", "empty field name"),
+                                 ("$.a[1", "unclosed '['"),
+                                 ("$['a", "unclosed '['"),
+                                 ("$['a'b]", "unclosed '['"),
+                                 ("$[x]", "the index is not a number"),
+                                 ("$x", "unexpected 'x'")] {
+            assert_eq!(parse_path(path), Err(format!("Invalid JSON path '{}': {}", path, reason)));
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let c = Column::parse("status=$.order.status").unwrap();
+        assert_eq!((c.name.as_str(), c.path, c.column_type), ("status", vec![field("order"), field("status")], ColumnType::Text));
+        let c = Column::parse("_n2=$.n:integer").unwrap();
+        assert_eq!((c.name.as_str(), c.column_type), ("_n2", ColumnType::Integer));
+        // A colon in a quoted field is not the start of the type.
+        let c = Column::parse("t=$['a:b']").unwrap();
+        assert_eq!((c.path, c.column_type), (vec![field("a:b")], ColumnType::Text));
+        let c = Column::parse("t=$['a:b']:json").unwrap();
+        assert_eq!((c.path, c.column_type), (vec![field("a:b")], ColumnType::Json));
+    }
+
+    #[test]
+    fn invalid_columns() {
+        let error = |s: &str| Column::parse(s).err().unwrap();
+        assert_eq!(error("status"), "'status' is not a column like 'name=$.path:type'");
+        assert_eq!(error("offset=$.offset"), "The column name 'offset' is reserved");
+        assert_eq!(error("Headers=$.h"), "The column name 'Headers' is reserved");
+        assert_eq!(error("1st=$.a"), "'1st' is not a valid column name");
+        assert_eq!(error("a-b=$.a"), "'a-b' is not a valid column name");
+        assert_eq!(error("=$.a"), "'' is not a valid column name");
+        assert_eq!(error("a=$.a:float"), "Unknown column type 'float'. Use 'text', 'integer', 'real' or 'json'");
+        assert_eq!(error("a="), "Invalid JSON path '': it must start with '$'");
+    }
+
+    #[test]
+    fn duplicate_columns() {
+        let columns = |names: &[&str]| names.iter().map(|n| Column::parse(&format!("{}=$.id", n)).unwrap()).collect::<Vec<_>>();
+        assert_eq!(check_columns(&columns(&["a", "b"])), Ok(()));
+        assert_eq!(check_columns(&columns(&["a", "b", "A"])), Err("The column 'A' is given more than once".to_string()));
+    }
+
+    #[test]
+    fn extract() {
+        let value = json!({"order": {"id": "42", "total": 3.0, "tax": 0.5, "paid": true, "items": [{"sku id": "x"}]}});
+        let extract = |spec: &str| Column::parse(spec).unwrap().extract(&value);
+        assert_eq!(extract("c=$.order.items[0]['sku id']"), SqlValue::Text("x".to_string()));
+        assert_eq!(extract("c=$.order.items[1]['sku id']"), SqlValue::Null);
+        assert_eq!(extract("c=$.order.missing"), SqlValue::Null);
+        assert_eq!(extract("c=$.order.id:integer"), SqlValue::Integer(42));
+        assert_eq!(extract("c=$.order.total:integer"), SqlValue::Integer(3));
+        assert_eq!(extract("c=$.order.tax:integer"), SqlValue::Null);
+        assert_eq!(extract("c=$.order.paid:integer"), SqlValue::Integer(1));
+        assert_eq!(extract("c=$.order.id:real"), SqlValue::Real(42.0));
+        assert_eq!(extract("c=$.order.total"), SqlValue::Text("3.0".to_string()));
+        assert_eq!(extract("c=$.order.items:json"), SqlValue::Text("[{\"sku id\":\"x\"}]".to_string()));
+    }
+
+    #[test]
+    fn merged_types() {
+        use super::ColumnType::*;
+        assert_eq!(merge_types(None, None), None);
+        assert_eq!(merge_types(None, Some(Real)), Some(Real));
+        assert_eq!(merge_types(Some(Integer), Some(Integer)), Some(Integer));
+        assert_eq!(merge_types(Some(Integer), Some(Real)), Some(Real));
+        assert_eq!(merge_types(Some(Real), Some(Integer)), Some(Real));
+        assert_eq!(merge_types(Some(Integer), Some(Text)), Some(Text));
+        assert_eq!(merge_types(Some(Text), Some(Real)), Some(Text));
+        assert_eq!(merge_types(Some(Json), Some(Integer)), Some(Json));
+        assert_eq!(merge_types(Some(Text), Some(Json)), Some(Json));
+    }
+
+    #[test]
+    fn inferred_columns() {
+        let samples = vec![
+            json!({"id": 1, "price": 2, "name": "a", "tags": ["x"], "offset": 5, "2nd": true, "a-b": "x", "a_b": "y", "none": null}),
+            json!({"id": 2, "price": 2.5, "name": 3, "tags": null}),
+            json!("not an object")
+        ];
+        let existing = vec![Column::parse("Name=$.name").unwrap()];
+        let mut columns: Vec<(String, Vec<Step>, ColumnType)> = infer_columns(&samples, &existing).into_iter()
+            .map(|c| (c.name, c.path, c.column_type))
+            .collect();
+        columns.sort_by(|a, b| a.0.cmp(&b.0));
+        let column = |name: &str, field: &str, column_type| (name.to_string(), vec![Step::Field(field.to_string())], column_type);
+        let mut expected = vec![
+            column("_2nd", "2nd", ColumnType::Integer),
+            column("id", "id", ColumnType::Integer),
+            column("price", "price", ColumnType::Real),
+            // Taken by the existing column.
+            column("name_", "name", ColumnType::Text),
+            column("tags", "tags", ColumnType::Json),
+            // Reserved.
+            column("offset_", "offset", ColumnType::Integer),
+            column("none", "none", ColumnType::Text),
+            // The field which comes first gets the name.
+            column("a_b", "a-b", ColumnType::Text),
+            column("a_b_", "a_b", ColumnType::Text)
+        ];
+        expected.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(columns, expected);
+    }
+}
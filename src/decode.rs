@@ -0,0 +1,260 @@
+//! Decoding of message keys and values before they are stored.
+
+use std::borrow::Cow;
+use std::str;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json;
+use rmpv;
+use avro::SchemaStore;
+use protobuf::ProtobufDecoder;
+use error::{Error, Result};
+use value::{SqlType, SqlValue};
+
+/// The format of message keys or values.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// The bytes are stored as they are.
+    Raw,
+    /// UTF-8 text.
+    Utf8,
+    /// JSON text. It is validated and stored as text.
+    Json,
+    /// A signed 64 bit big endian integer.
+    Int64Be,
+    /// Avro in the Confluent wire format. It is stored as JSON.
+    Avro,
+    /// Protobuf messages of a single type. They are stored as JSON.
+    Protobuf,
+    /// MessagePack. It is stored as JSON, see `msgpack_to_json`.
+    Msgpack
+}
+
+impl Format {
+    pub fn parse(s: &str) -> ::std::result::Result<Format, String> {
+        match s {
+            "raw" => Ok(Format::Raw),
+            "utf8" => Ok(Format::Utf8),
+            "json" => Ok(Format::Json),
+            "int64-be" => Ok(Format::Int64Be),
+            "avro" => Ok(Format::Avro),
+            "protobuf" => Ok(Format::Protobuf),
+            "msgpack" => Ok(Format::Msgpack),
+            _ => Err(format!("Unknown format '{}'. Use 'raw', 'utf8', 'json', 'int64-be', 'avro', 'protobuf' or 'msgpack'", s))
+        }
+    }
+
+    /// The type of the column the decoded data is stored in.
+    pub fn sql_type(&self) -> SqlType {
+        match *self {
+            Format::Raw => SqlType::Blob,
+            Format::Int64Be => SqlType::Integer,
+            Format::Utf8 => SqlType::Text,
+            Format::Json | Format::Avro | Format::Protobuf | Format::Msgpack => SqlType::Json
+        }
+    }
+}
+
+/// A decoded message key or value.
+pub enum Decoded {
+    /// Bytes which are not decoded. They may still be JSON.
+    Raw(Vec<u8>),
+    Text(String),
+    Integer(i64),
+    Json(serde_json::Value),
+    /// An empty key or value, which Kafka does not distinguish from a
+    /// missing one.
+    Null
+}
+
+impl Decoded {
+    /// The value stored in the `key` or `value` column.
+    pub fn to_sql(&self) -> SqlValue {
+        match *self {
+            Decoded::Raw(ref bytes) => SqlValue::Blob(bytes.clone()),
+            Decoded::Text(ref text) => SqlValue::Text(text.clone()),
+            Decoded::Integer(i) => SqlValue::Integer(i),
+            Decoded::Json(ref json) => SqlValue::Text(json.to_string()),
+            Decoded::Null => SqlValue::Null
+        }
+    }
+
+    /// The data as JSON, if it is JSON.
+    pub fn json<'a>(&'a self) -> Option<Cow<'a, serde_json::Value>> {
+        match *self {
+            Decoded::Raw(ref bytes) => serde_json::from_slice(bytes).ok().map(Cow::Owned),
+            Decoded::Text(ref text) => serde_json::from_str(text).ok().map(Cow::Owned),
+            Decoded::Integer(i) => Some(Cow::Owned(json!(i))),
+            Decoded::Json(ref json) => Some(Cow::Borrowed(json)),
+            Decoded::Null => None
+        }
+    }
+}
+
+pub struct Decoder {
+    format: Format,
+    schemas: Option<SchemaStore>,
+    protobuf: Option<ProtobufDecoder>
+}
+
+impl Decoder {
+    /// `schema_registry` is the directory or URL Avro schemas are read from.
+    /// Protobuf messages of `message_type` are decoded with the descriptors
+    /// in `descriptor_set`.
+    pub fn new(format: Format,
+               schema_registry: Option<&str>,
+               descriptor_set: Option<&str>,
+               message_type: Option<&str>) -> Result<Decoder> {
+        let mut decoder = Decoder {
+            format,
+            schemas: None,
+            protobuf: None
+        };
+        match format {
+            Format::Avro => match schema_registry {
+                Some(location) => decoder.schemas = Some(SchemaStore::new(location)),
+                None => return Err(Error::Args("Avro needs a --schema-registry".to_string()))
+            },
+            Format::Protobuf => match (descriptor_set, message_type) {
+                (Some(descriptor_set), Some(message_type)) =>
+                    decoder.protobuf = Some(ProtobufDecoder::new(descriptor_set, message_type)?),
+                _ => return Err(Error::Args("Protobuf needs a --descriptor-set and a message type".to_string()))
+            },
+            _ => {}
+        }
+        Ok(decoder)
+    }
+
+    pub fn decode(&mut self, bytes: &[u8]) -> Result<Decoded> {
+        match self.format {
+            Format::Raw => Ok(Decoded::Raw(bytes.to_vec())),
+            // An empty protobuf message has all fields set to their defaults.
+            _ if bytes.is_empty() && self.format != Format::Protobuf => Ok(Decoded::Null),
+            Format::Utf8 => str::from_utf8(bytes)
+                .map(|text| Decoded::Text(text.to_string()))
+                .map_err(|e| Error::Decode(format!("invalid UTF-8: {}", e))),
+            Format::Json => serde_json::from_slice(bytes)
+                .map(Decoded::Json)
+                .map_err(|e| Error::Decode(format!("invalid JSON: {}", e))),
+            Format::Int64Be => {
+                if bytes.len() != 8 {
+                    return Err(Error::Decode(format!("a 64 bit integer has 8 bytes, not {}", bytes.len())));
+                }
+                let mut be = [0; 8];
+                be.copy_from_slice(bytes);
+                Ok(Decoded::Integer(i64::from_be_bytes(be)))
+            }
+            Format::Avro => self.schemas.as_mut().unwrap().decode(bytes).map(Decoded::Json),
+            Format::Protobuf => self.protobuf.as_ref().unwrap().decode(bytes).map(Decoded::Json),
+            Format::Msgpack => {
+                let mut rest = bytes;
+                let value = rmpv::decode::read_value(&mut rest)
+                    .map_err(|e| Error::Decode(format!("invalid MessagePack: {}", e)))?;
+                if !rest.is_empty() {
+                    return Err(Error::Decode(format!("invalid MessagePack: {} bytes after the value", rest.len())));
+                }
+                Ok(Decoded::Json(msgpack_to_json(value)))
+            }
+        }
+    }
+}
+
+/// Converts MessagePack to JSON. Binary data and the data of extension
+/// types are Base64 encoded and map keys, which need not be strings in
+/// MessagePack, are converted to strings. Strings of invalid UTF-8 and
+/// floats without JSON representation are replaced.
+fn msgpack_to_json(value: rmpv::Value) -> serde_json::Value {
+    use rmpv::Value;
+    match value {
+        Value::Nil => serde_json::Value::Null,
+        Value::Boolean(b) => serde_json::Value::Bool(b),
+        Value::Integer(i) => match i.as_i64() {
+            Some(i) => json!(i),
+            None => json!(i.as_u64())
+        },
+        Value::F32(f) => json!(f as f64),
+        Value::F64(f) => json!(f),
+        Value::String(s) => serde_json::Value::String(String::from_utf8_lossy(s.as_bytes()).into_owned()),
+        Value::Binary(bytes) | Value::Ext(_, bytes) => serde_json::Value::String(STANDARD.encode(bytes)),
+        Value::Array(values) => serde_json::Value::Array(values.into_iter().map(msgpack_to_json).collect()),
+        Value::Map(entries) => serde_json::Value::Object(entries.into_iter()
+            .map(|(key, value)| {
+                let key = match msgpack_to_json(key) {
+                    serde_json::Value::String(s) => s,
+                    key => key.to_string()
+                };
+                (key, msgpack_to_json(value))
+            })
+            .collect())
+    }
+}
+
+/// The decoders for the keys and the values of the messages.
+pub struct Decoders {
+    pub key: Decoder,
+    pub value: Decoder
+}
+
+#[cfg(test)]
+mod tests {
+    use error::{Error, Result};
+    use super::{Decoded, Decoder, Format};
+
+    fn decode(format: Format, bytes: &[u8]) -> Result<Decoded> {
+        Decoder::new(format, None, None, None).unwrap().decode(bytes)
+    }
+
+    fn json(format: Format, bytes: &[u8]) -> String {
+        match decode(format, bytes) {
+            Ok(Decoded::Json(json)) => json.to_string(),
+            Ok(_) => panic!("not decoded as JSON"),
+            Err(e) => panic!("{}", e)
+        }
+    }
+
+    fn decode_error(format: Format, bytes: &[u8]) -> String {
+        match decode(format, bytes) {
+            Err(Error::Decode(msg)) => msg,
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("decoded")
+        }
+    }
+
+    #[test]
+    fn msgpack() {
+        // {"a": [1, -1, 1.5, true, nil]}
+        assert_eq!(json(Format::Msgpack, &[0x81, 0xa1, 0x61, 0x95, 0x01, 0xff, 0xcb, 0x3f, 0xf8, 0, 0, 0, 0, 0, 0, 0xc3, 0xc0]),
+                   r#"{"a":[1,-1,1.5,true,null]}"#);
+        // {1: "a"}
+        assert_eq!(json(Format::Msgpack, &[0x81, 0x01, 0xa1, 0x61]), r#"{"1":"a"}"#);
+        // {"b": bin [1, 2]} and an extension of type 1.
+        assert_eq!(json(Format::Msgpack, &[0x81, 0xa1, 0x62, 0xc4, 0x02, 0x01, 0x02]), r#"{"b":"AQI="}"#);
+        assert_eq!(json(Format::Msgpack, &[0xd4, 0x01, 0xff]), r#""/w==""#);
+        assert!(decode_error(Format::Msgpack, &[0x92, 0x01]).contains("invalid MessagePack"));
+        assert!(decode_error(Format::Msgpack, &[0x01, 0x02]).contains("1 bytes after the value"));
+    }
+
+    #[test]
+    fn utf8() {
+        match decode(Format::Utf8, "caf\u{e9}".as_bytes()) {
+            Ok(Decoded::Text(text)) => assert_eq!(text, "caf\u{e9}"),
+            _ => panic!("not decoded as text")
+        }
+        assert!(decode_error(Format::Utf8, &[0x63, 0xff]).starts_with("invalid UTF-8"));
+    }
+
+    #[test]
+    fn int64_be() {
+        match decode(Format::Int64Be, &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe]) {
+            Ok(Decoded::Integer(i)) => assert_eq!(i, -2),
+            _ => panic!("not decoded as an integer")
+        }
+        assert_eq!(decode_error(Format::Int64Be, &[0, 1]), "a 64 bit integer has 8 bytes, not 2");
+    }
+
+    #[test]
+    fn empty() {
+        assert!(matches!(decode(Format::Json, b""), Ok(Decoded::Null)));
+        assert!(matches!(decode(Format::Raw, b""), Ok(Decoded::Raw(ref bytes)) if bytes.is_empty()));
+    }
+}
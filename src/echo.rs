@@ -0,0 +1,347 @@
+//! Echoing the dumped messages to stdout.
+
+use std::io::{self, stdout, BufWriter, ErrorKind, Write};
+use std::str;
+use serde_json::{Map, Value};
+use error::{Error, Result};
+use source::Record;
+
+/// A part of an echo template.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Literal(String),
+    Topic,
+    Partition,
+    Offset,
+    Timestamp,
+    Key,
+    Value,
+    KeyLength,
+    ValueLength
+}
+
+/// How a message is printed.
+#[derive(Clone)]
+pub enum Format {
+    /// Topic, partition, offset and value separated by spaces.
+    Compact,
+    /// One JSON object per line, which can be read back with
+    /// `--from-file`.
+    Json,
+    /// A kcat like template.
+    Template(Vec<Token>)
+}
+
+/// How keys and values which are not valid UTF-8 are printed.
+#[derive(Clone, Copy)]
+pub enum Binary {
+    /// Invalid sequences are replaced with U+FFFD.
+    Lossy,
+    /// All bytes are printed as hex digits.
+    Hex
+}
+
+impl Format {
+    /// Parses `compact`, `json` or a template. The template may contain
+    /// `%t` (topic), `%p` (partition), `%o` (offset), `%T` (timestamp), `%k`
+    /// (key), `%s` (value), `%K` (key length), `%S` (value length), `%%`,
+    /// `\n`, `\t` and `\\`.
+    pub fn parse(s: &str) -> ::std::result::Result<Format, String> {
+        match s {
+            "compact" => return Ok(Format::Compact),
+            "json" => return Ok(Format::Json),
+            _ => {}
+        }
+        let mut tokens = vec![];
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '%' => match chars.next() {
+                    Some('t') => Token::Topic,
+                    Some('p') => Token::Partition,
+                    Some('o') => Token::Offset,
+                    Some('T') => Token::Timestamp,
+                    Some('k') => Token::Key,
+                    Some('s') => Token::Value,
+                    Some('K') => Token::KeyLength,
+                    Some('S') => Token::ValueLength,
+                    Some('%') => { literal.push('%'); continue; }
+                    Some(x) => return Err(format!("Unknown format sequence '%{}'", x)),
+                    None => return Err("The format ends with '%'".to_string())
+                },
+                '\\' => match chars.next() {
+                    Some('n') => { literal.push('\n'); continue; }
+                    Some('t') => { literal.push('\t'); continue; }
+                    Some('\\') => { literal.push('\\'); continue; }
+                    Some(x) => return Err(format!("Unknown escape sequence '\\{}'", x)),
+                    None => return Err("The format ends with '\\'".to_string())
+                },
+                c => { literal.push(c); continue; }
+            };
+            if !literal.is_empty() {
+                tokens.push(Token::Literal(literal));
+                literal = String::new();
+            }
+            tokens.push(token);
+        }
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(literal));
+        }
+        Ok(Format::Template(tokens))
+    }
+}
+
+impl Binary {
+    pub fn parse(s: &str) -> ::std::result::Result<Binary, String> {
+        match s {
+            "lossy" => Ok(Binary::Lossy),
+            "hex" => Ok(Binary::Hex),
+            _ => Err(format!("'{}' is neither 'lossy' nor 'hex'", s))
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub struct Echo {
+    format: Format,
+    binary: Binary,
+    max_bytes: Option<usize>,
+    out: Box<dyn Write>,
+    /// Whether the reader of stdout went away, like `head` does. The dump
+    /// goes on without echoing.
+    closed: bool
+}
+
+impl Echo {
+    /// Keys and values longer than `max_bytes` are truncated.
+    pub fn new(format: Format, binary: Binary, max_bytes: Option<usize>) -> Echo {
+        Echo {
+            format,
+            binary,
+            max_bytes,
+            out: Box::new(BufWriter::new(stdout())),
+            closed: false
+        }
+    }
+
+    pub fn write(&mut self, r: &Record) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = self.write_record(r);
+        self.check(result)
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.closed {
+            return Ok(());
+        }
+        let result = self.out.flush();
+        self.check(result)
+    }
+
+    /// Stops echoing, when stdout was closed. Other errors are errors of the
+    /// output.
+    fn check(&mut self, result: io::Result<()>) -> Result<()> {
+        match result {
+            Err(ref e) if e.kind() == ErrorKind::BrokenPipe => {
+                self.closed = true;
+                Ok(())
+            }
+            result => result.map_err(Error::Output)
+        }
+    }
+
+    fn write_record(&mut self, r: &Record) -> io::Result<()> {
+        match self.format {
+            Format::Compact => {
+                let value = self.text(&r.value);
+                writeln!(self.out, "{} {} {} {}", r.topic, r.partition, r.offset, value)
+            }
+            Format::Json => {
+                let line = self.json(r);
+                writeln!(self.out, "{}", line)
+            }
+            Format::Template(ref tokens) => {
+                for token in tokens {
+                    match *token {
+                        Token::Literal(ref s) => write!(self.out, "{}", s)?,
+                        Token::Topic => write!(self.out, "{}", r.topic)?,
+                        Token::Partition => write!(self.out, "{}", r.partition)?,
+                        Token::Offset => write!(self.out, "{}", r.offset)?,
+                        // Like kcat, -1 stands for a missing timestamp.
+                        Token::Timestamp => write!(self.out, "{}", r.timestamp.unwrap_or(-1))?,
+                        Token::Key => write!(self.out, "{}", self.text(&r.key))?,
+                        Token::Value => write!(self.out, "{}", self.text(&r.value))?,
+                        Token::KeyLength => write!(self.out, "{}", r.key.len())?,
+                        Token::ValueLength => write!(self.out, "{}", r.value.len())?
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The JSON object of a message. Empty keys and values are `null`, as
+    /// Kafka does not distinguish them from missing ones.
+    pub fn json(&self, r: &Record) -> Value {
+        let mut object = Map::new();
+        object.insert("topic".to_string(), json!(r.topic));
+        object.insert("partition".to_string(), json!(r.partition));
+        object.insert("offset".to_string(), json!(r.offset));
+        object.insert("timestamp".to_string(), json!(r.timestamp));
+        object.insert("timestamp_type".to_string(), json!(r.timestamp_type.map(|t| t.name())));
+        self.insert_bytes(&mut object, "key", Some(&r.key[..]).filter(|k| !k.is_empty()));
+        self.insert_bytes(&mut object, "value", Some(&r.value[..]).filter(|v| !v.is_empty()));
+        let headers = r.headers.iter()
+            .map(|(name, value)| {
+                let mut header = Map::new();
+                header.insert("name".to_string(), json!(name));
+                self.insert_bytes(&mut header, "value", value.as_deref());
+                Value::Object(header)
+            })
+            .collect();
+        object.insert("headers".to_string(), Value::Array(headers));
+        Value::Object(object)
+    }
+
+    /// Adds `bytes` to `object` as `name`, when they are UTF-8 text, or else
+    /// as hex digits in `<name>_hex`, so they are read back unchanged.
+    fn insert_bytes(&self, object: &mut Map<String, Value>, name: &str, bytes: Option<&[u8]>) {
+        match bytes {
+            Some(bytes) if str::from_utf8(bytes).is_err() => {
+                object.insert(format!("{}_hex", name), json!(hex(bytes)));
+            }
+            bytes => {
+                object.insert(name.to_string(), json!(bytes.map(|b| self.text(b))));
+            }
+        }
+    }
+
+    fn text(&self, bytes: &[u8]) -> String {
+        let (bytes, truncated) = match self.max_bytes {
+            Some(max) if bytes.len() > max => (&bytes[..max], true),
+            _ => (bytes, false)
+        };
+        let mut text = match str::from_utf8(bytes) {
+            Ok(s) => s.to_string(),
+            // Truncation may have split the last character.
+            Err(ref e) if truncated && e.error_len().is_none() =>
+                str::from_utf8(&bytes[..e.valid_up_to()]).unwrap().to_string(),
+            Err(_) => match self.binary {
+                Binary::Lossy => String::from_utf8_lossy(bytes).into_owned(),
+                Binary::Hex => hex(bytes)
+            }
+        };
+        if truncated {
+            text.push_str("...");
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, ErrorKind, Write};
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use tests::record;
+    use super::{Binary, Echo, Format, Token};
+    use super::Token::*;
+
+    /// What was written to stdout and whether it was closed.
+    #[derive(Default)]
+    struct Pipe {
+        written: RefCell<Vec<u8>>,
+        closed: Cell<bool>
+    }
+
+    /// Stdout, which fails with `kind` once the pipe is closed.
+    struct Stdout {
+        pipe: Rc<Pipe>,
+        kind: ErrorKind
+    }
+
+    impl Write for Stdout {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.pipe.closed.get() {
+                return Err(io::Error::new(self.kind, "closed"));
+            }
+            self.pipe.written.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// An echo in the compact format, which writes into the returned pipe.
+    fn piped_echo(kind: ErrorKind) -> (Echo, Rc<Pipe>) {
+        let pipe = Rc::new(Pipe::default());
+        let mut echo = Echo::new(Format::Compact, Binary::Lossy, None);
+        echo.out = Box::new(Stdout { pipe: pipe.clone(), kind });
+        (echo, pipe)
+    }
+
+    fn template(s: &str) -> Vec<Token> {
+        match Format::parse(s).unwrap() {
+            Format::Template(tokens) => tokens,
+            _ => panic!("'{}' is not a template", s)
+        }
+    }
+
+    fn literal(s: &str) -> Token {
+        Literal(s.to_string())
+    }
+
+    #[test]
+    fn closed_stdout() {
+        let (mut echo, pipe) = piped_echo(ErrorKind::BrokenPipe);
+        echo.write(&record(0, 0, "k", "v")).unwrap();
+        pipe.closed.set(true);
+        echo.write(&record(0, 1, "k", "v")).unwrap();
+        echo.write(&record(0, 2, "k", "v")).unwrap();
+        echo.flush().unwrap();
+        assert_eq!(String::from_utf8(pipe.written.borrow().clone()).unwrap(), "orders 0 0 v\n");
+
+        let (mut echo, pipe) = piped_echo(ErrorKind::Other);
+        pipe.closed.set(true);
+        assert_eq!(echo.write(&record(0, 0, "k", "v")).unwrap_err().exit_code(), 3);
+    }
+
+    #[test]
+    fn keywords() {
+        assert!(matches!(Format::parse("compact"), Ok(Format::Compact)));
+        assert!(matches!(Format::parse("json"), Ok(Format::Json)));
+        assert_eq!(template("jsonl"), vec![literal("jsonl")]);
+    }
+
+    #[test]
+    fn placeholders() {
+        assert_eq!(template("%t%p%o%T%k%s%K%S"),
+                   vec![Topic, Partition, Offset, Timestamp, Key, Value, KeyLength, ValueLength]);
+        assert_eq!(template(r"Topic %t [%p] at %o: %s\n"),
+                   vec![literal("Topic "), Topic, literal(" ["), Partition, literal("] at "), Offset, literal(": "), Value, literal("\n")]);
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(template(r"100%%\t\\n\\"), vec![literal("100%\t\\n\\")]);
+        assert_eq!(template("%%s"), vec![literal("%s")]);
+        assert_eq!(template(""), vec![]);
+    }
+
+    #[test]
+    fn invalid_templates() {
+        let error = |s: &str| Format::parse(s).err().unwrap();
+        assert_eq!(error("%t %x"), "Unknown format sequence '%x'");
+        assert_eq!(error("%s %"), "The format ends with '%'");
+        assert_eq!(error(r"%s\r"), "Unknown escape sequence '\\r'");
+        assert_eq!(error(r"%s\"), "The format ends with '\\'");
+    }
+}
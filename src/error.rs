@@ -0,0 +1,144 @@
+//! The errors which stop a dump and the exit codes they map to.
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use rdkafka::error::KafkaError;
+use rusqlite;
+#[cfg(feature = "postgres")]
+use postgres;
+#[cfg(feature = "mysql")]
+use mysql;
+#[cfg(feature = "duckdb")]
+use duckdb;
+#[cfg(feature = "parquet")]
+use arrow_schema::ArrowError;
+#[cfg(feature = "parquet")]
+use parquet::errors::ParquetError;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The command line arguments are invalid or do not match the cluster.
+    Args(String),
+    /// Talking to the Kafka cluster failed.
+    Kafka(KafkaError),
+    /// Reading or writing the output failed.
+    Sqlite(rusqlite::Error),
+    #[cfg(feature = "postgres")]
+    Postgres(postgres::Error),
+    #[cfg(feature = "mysql")]
+    Mysql(mysql::Error),
+    #[cfg(feature = "duckdb")]
+    Duckdb(duckdb::Error),
+    #[cfg(feature = "parquet")]
+    Parquet(ParquetError),
+    /// Writing the files of the output failed. It exits like the errors of
+    /// the databases, not like those of the input files.
+    Output(io::Error),
+    /// Reading an input file, like a descriptor set, failed.
+    Io(io::Error),
+    /// A message could not be decoded.
+    Decode(String)
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+impl Error {
+    /// The exit code of the process, when it stops because of this error.
+    /// The codes are listed in the help text.
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            Error::Args(_) => 1,
+            Error::Kafka(_) => 2,
+            Error::Sqlite(_) => 3,
+            #[cfg(feature = "postgres")]
+            Error::Postgres(_) => 3,
+            #[cfg(feature = "mysql")]
+            Error::Mysql(_) => 3,
+            #[cfg(feature = "duckdb")]
+            Error::Duckdb(_) => 3,
+            #[cfg(feature = "parquet")]
+            Error::Parquet(_) => 3,
+            Error::Output(_) => 3,
+            Error::Io(_) => 4,
+            Error::Decode(_) => 5
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Args(ref msg) => write!(f, "{}", msg),
+            Error::Kafka(ref e) => write!(f, "Kafka: {}", e),
+            Error::Sqlite(ref e) => write!(f, "SQLite: {}", e),
+            #[cfg(feature = "postgres")]
+            Error::Postgres(ref e) => write!(f, "PostgreSQL: {}", e),
+            #[cfg(feature = "mysql")]
+            Error::Mysql(ref e) => write!(f, "MySQL: {}", e),
+            #[cfg(feature = "duckdb")]
+            Error::Duckdb(ref e) => write!(f, "DuckDB: {}", e),
+            #[cfg(feature = "parquet")]
+            Error::Parquet(ref e) => write!(f, "Parquet: {}", e),
+            Error::Output(ref e) => write!(f, "Output: {}", e),
+            Error::Io(ref e) => write!(f, "IO: {}", e),
+            Error::Decode(ref msg) => write!(f, "Decoding: {}", msg)
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<KafkaError> for Error {
+    fn from(e: KafkaError) -> Error {
+        Error::Kafka(e)
+    }
+}
+
+impl From<rusqlite::Error> for Error {
+    fn from(e: rusqlite::Error) -> Error {
+        Error::Sqlite(e)
+    }
+}
+
+#[cfg(feature = "postgres")]
+impl From<postgres::Error> for Error {
+    fn from(e: postgres::Error) -> Error {
+        Error::Postgres(e)
+    }
+}
+
+#[cfg(feature = "mysql")]
+impl From<mysql::Error> for Error {
+    fn from(e: mysql::Error) -> Error {
+        Error::Mysql(e)
+    }
+}
+
+#[cfg(feature = "duckdb")]
+impl From<duckdb::Error> for Error {
+    fn from(e: duckdb::Error) -> Error {
+        Error::Duckdb(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<ParquetError> for Error {
+    fn from(e: ParquetError) -> Error {
+        Error::Parquet(e)
+    }
+}
+
+#[cfg(feature = "parquet")]
+impl From<ArrowError> for Error {
+    fn from(e: ArrowError) -> Error {
+        Error::Parquet(e.into())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
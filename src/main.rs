@@ -1,140 +1,920 @@
 //! dump-kafka-into-sqlite
 //! ======================
-//!
-//! Todo
-//!   * Error handling
-//!   * Print progress
-//!   * Extract JSON paths from the value into table columns
 
-extern crate kafka;
+extern crate rdkafka;
 extern crate clap;
 extern crate rusqlite;
+#[cfg(feature = "postgres")]
+extern crate postgres;
+#[cfg(feature = "mysql")]
+extern crate mysql;
+#[cfg(feature = "duckdb")]
+extern crate duckdb;
+#[cfg(feature = "parquet")]
+extern crate parquet;
+#[cfg(feature = "parquet")]
+extern crate arrow_array;
+#[cfg(feature = "parquet")]
+extern crate arrow_schema;
+extern crate base64;
+extern crate regex;
+extern crate chrono;
+#[macro_use]
+extern crate serde_json;
+extern crate apache_avro;
+extern crate ureq;
+extern crate prost_reflect;
+extern crate rmpv;
+#[cfg(test)]
+extern crate tempfile;
 
-use std::thread;
-use std::sync::mpsc::{sync_channel, SyncSender, Receiver};
-use std::fs::remove_file;
-use kafka::consumer::{Consumer, FetchOffset, MessageSets};
-use clap::{Arg, App, AppSettings};
-use rusqlite::Connection;
-use std::path::Path;
+mod avro;
+mod columns;
+mod decode;
+mod echo;
+mod error;
+mod progress;
+mod protobuf;
+mod sink;
+mod source;
+mod value;
+
+use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{sync_channel, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal};
+use std::mem;
+use clap::{Arg, ArgGroup, App, AppSettings};
+use std::process::exit;
+use regex::Regex;
+use chrono::DateTime;
+use error::{Error, Result};
+use progress::Progress;
+use echo::Echo;
+use columns::{Column, check_columns, infer_columns};
+use sink::{Row, Sink};
+use source::{Record, Source};
+use value::SqlValue;
+use decode::{Decoded, Decoder, Decoders};
+
+/// How often the data is committed in follow mode.
+const COMMIT_INTERVAL_MS: u64 = 1_000;
 
 #[derive(Clone)]
 struct Args {
     brokers: Vec<String>,
-    topic: String,
+    /// A file to read the messages from instead of Kafka.
+    from_file: Option<String>,
+    topics: Vec<String>,
+    topic_pattern: Option<String>,
     output: String,
-    compact: bool
+    format: sink::Format,
+    /// The size in bytes at which a new output file is started.
+    max_file_size: Option<usize>,
+    /// Only given for CSV and JSON Lines.
+    binary_encoding: Option<sink::BinaryEncoding>,
+    compact: bool,
+    follow: bool,
+    resume: bool,
+    overwrite: bool,
+    progress: bool,
+    echo: Option<echo::Format>,
+    echo_binary: echo::Binary,
+    echo_max_bytes: Option<usize>,
+    columns: Vec<Column>,
+    infer_schema: bool,
+    sample_size: usize,
+    key_format: decode::Format,
+    value_format: decode::Format,
+    schema_registry: Option<String>,
+    descriptor_set: Option<String>,
+    key_message_type: Option<String>,
+    message_type: Option<String>,
+    from_time: Option<i64>,
+    until_time: Option<i64>,
+    partitions: Vec<PartitionSpec>,
+    start_offset: Option<i64>,
+    end_offset: Option<i64>
+}
+
+/// A partition to read, optionally restricted to a range of offsets.
+#[derive(Clone)]
+struct PartitionSpec {
+    partition: i32,
+    /// The first offset to read.
+    start: Option<i64>,
+    /// The last offset to read.
+    end: Option<i64>
+}
+
+/// Parses a point in time given as RFC 3339 string or as milliseconds since
+/// the epoch. Returns the milliseconds since the epoch.
+fn parse_time(s: &str) -> std::result::Result<i64, String> {
+    match s.parse::<i64>() {
+        Ok(millis) => Ok(millis),
+        Err(_) => DateTime::parse_from_rfc3339(s)
+            .map(|t| t.timestamp_millis())
+            .map_err(|e| format!("'{}' is neither an RFC 3339 time nor milliseconds since the epoch: {}", s, e))
+    }
+}
+
+/// Parses an offset, which is never negative.
+fn parse_offset(s: &str) -> std::result::Result<i64, String> {
+    match s.parse::<i64>() {
+        Ok(offset) if offset >= 0 => Ok(offset),
+        _ => Err(format!("'{}' is not an offset, which is a number of at least 0", s))
+    }
+}
+
+/// Parses a partition given as `P`, `P:START-END`, `P:START-` or `P:-END`.
+fn parse_partition_spec(s: &str) -> std::result::Result<PartitionSpec, String> {
+    let invalid = || format!("'{}' is not a partition like '3' or '3:1200-5000'", s);
+    let parse_offset = |o: &str| if o.is_empty() {
+        Ok(None)
+    } else {
+        parse_offset(o).map(Some).map_err(|_| invalid())
+    };
+    let mut parts = s.splitn(2, ':');
+    let partition = parts.next().unwrap().parse::<i32>().map_err(|_| invalid())?;
+    let (start, end) = match parts.next() {
+        Some(range) => {
+            let mut offsets = range.splitn(2, '-');
+            let start = parse_offset(offsets.next().unwrap())?;
+            let end = parse_offset(offsets.next().ok_or_else(&invalid)?)?;
+            (start, end)
+        }
+        None => (None, None)
+    };
+    if let (Some(start), Some(end)) = (start, end) {
+        if start > end {
+            return Err(format!("The range of '{}' ends before it starts", s));
+        }
+    }
+    Ok(PartitionSpec {
+        partition,
+        start,
+        end
+    })
 }
 
-fn parse_args() -> Args {
+fn parse_args<I, T>(argv: I) -> Args
+    where I: IntoIterator<Item = T>,
+          T: Into<OsString> + Clone
+{
     let matches = App::new("dump-kafka-to-sql")
         .version("0.0.1")
         .author("Wolfgang Ginolas <wolfgang.ginolas@gwif.eu>")
-        .about("Dump a Kafka topic into a SQLite database")
+        .about("Dump Kafka topics into a SQLite, DuckDB, PostgreSQL or MySQL database or into Parquet, CSV or JSON Lines files")
         .setting(AppSettings::ColoredHelp)
+        .after_help("EXIT CODES:
+    0    The dump is complete.
+    1    The arguments are invalid.
+    2    Kafka could not be read.
+    3    The output could not be written.
+    4    An input file could not be read.
+    5    A message could not be decoded.")
         .arg(Arg::with_name("BROKER")
              .short("b")
              .long("broker")
              .help("A Kafka broker. Multiple brokers can be specified. When no broker is given 'localhost:9092' is used.")
              .takes_value(true)
              .multiple(true))
+        .arg(Arg::with_name("FROM_FILE")
+             .long("from-file")
+             .help("Read the messages from a JSON Lines file instead of Kafka. Each line is an object like the ones printed by '--echo json', with 'topic', 'partition' and 'offset' and optionally 'timestamp', 'timestamp_type', 'key', 'value' and 'headers' like [{\"name\": \"trace\", \"value\": \"a1\"}]. Keys and values are text. Binary ones are given as 'key_base64', 'key_hex', 'value_base64' or 'value_hex' instead.")
+             .takes_value(true)
+             .conflicts_with("BROKER"))
         .arg(Arg::with_name("TOPIC")
              .short("t")
              .long("topic")
-             .help("The Kafka topic to read.")
+             .help("A Kafka topic to read. Multiple topics can be specified. Each topic is stored in its own table.")
+             .takes_value(true)
+             .multiple(true))
+        .arg(Arg::with_name("TOPIC_PATTERN")
+             .long("topic-pattern")
+             .help("A regular expression. All topics of the cluster whose whole name matches it are read, in addition to the topics given with '--topic'. Internal topics, whose names start with '__', are skipped.")
              .takes_value(true)
-             .required(true))
+             .validator(|p| Regex::new(&p).map(|_| ()).map_err(|e| e.to_string())))
+        .group(ArgGroup::with_name("TOPICS")
+               .args(&["TOPIC", "TOPIC_PATTERN"])
+               .multiple(true)
+               .required(true))
         .arg(Arg::with_name("OUTPUT")
              .short("o")
              .long("output")
-             .help("The output file name. When no file name is given 'dump.sqlite' is used.")
+             .help("The output SQLite file, a DuckDB file ending in '.duckdb' or the URL of a PostgreSQL or MySQL database like 'postgres://user@localhost/db' or 'mysql://user@localhost/db'. With the other formats it is a directory. When no output is given 'dump.sqlite' or 'dump' is used. DuckDB, PostgreSQL, MySQL and Parquet need a build with the cargo features 'duckdb', 'postgres', 'mysql' and 'parquet'.")
              .takes_value(true))
+        .arg(Arg::with_name("FORMAT")
+             .long("format")
+             .help("How the dump is stored: 'sql' writes the database given by '--output', 'parquet' and 'csv' write a file per topic and one for its headers, 'jsonl' writes a JSON Lines file per topic. Only 'sql' dumps can be compacted or resumed. When no format is given 'sql' is used.")
+             .takes_value(true)
+             .validator(|f| sink::Format::parse(&f).map(|_| ())))
+        .arg(Arg::with_name("MAX_FILE_SIZE")
+             .long("max-file-size")
+             .help("Start a new output file when the current one reaches this many bytes. The files are numbered like 'orders-00000.parquet'. In follow mode a Parquet file can only be read once a new one was started, so Parquet dumps need it there.")
+             .takes_value(true)
+             .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("BINARY_ENCODING")
+             .long("binary-encoding")
+             .help("How binary keys, values and headers are written into CSV and JSON Lines files: 'base64', 'hex' or 'utf8', which replaces invalid UTF-8. The default is 'base64'.")
+             .takes_value(true)
+             .validator(|e| sink::BinaryEncoding::parse(&e).map(|_| ())))
         .arg(Arg::with_name("c")
              .short("c")
              .long("compact")
-             .help("Only store the last message for each key. If the last message has no value, nothing is stored. This behaves like 'log.cleanup.policy=compact'."))
-        .get_matches();
+             .help("Only store the last message for each key. If the last message has no value, nothing is stored. This behaves like 'log.cleanup.policy=compact'. DuckDB tables have no unique constraint on the key, because the older messages are deleted while loading."))
+        .arg(Arg::with_name("f")
+             .short("f")
+             .long("follow")
+             .help("Do not stop at the end of the topics, but keep reading new messages. The data is committed periodically, so the database can be queried while it is written. The output is written in place instead of being replaced when the dump is complete."))
+        .arg(Arg::with_name("r")
+             .short("r")
+             .long("resume")
+             .help("Keep the existing output file and continue each partition after the last offset stored in it. A start offset or '--from-time' only applies, when it is later."))
+        .arg(Arg::with_name("OVERWRITE")
+             .long("overwrite")
+             .help("Replace the output, if it already exists. An output directory is deleted with all its files, unless it contains other files than those of a dump. In follow mode the existing output is deleted right away, before the new dump is written in its place. A new snapshot is written next to the output with the suffix '.partial', which is also replaced.")
+             .conflicts_with("r"))
+        .arg(Arg::with_name("NO_PROGRESS")
+             .long("no-progress")
+             .help("Do not print the progress to stderr."))
+        .arg(Arg::with_name("ECHO")
+             .long("echo")
+             .help("Print each message to stdout. The format is 'compact', 'json' for JSON lines, which can be read back with '--from-file', or a template like in kcat. The template may contain %t (topic), %p (partition), %o (offset), %T (timestamp), %k (key), %s (value), %K (key length), %S (value length), %%, \\n, \\t and \\\\. When stdout is closed, like by 'head', the dump goes on without printing.")
+             .takes_value(true)
+             .validator(|f| echo::Format::parse(&f).map(|_| ())))
+        .arg(Arg::with_name("ECHO_BINARY")
+             .long("echo-binary")
+             .help("How keys and values which are not valid UTF-8 are printed: 'lossy' replaces invalid bytes, 'hex' prints all bytes as hex digits. The default is 'lossy'. With '--echo json' they are always given as hex digits in 'key_hex' and 'value_hex'.")
+             .takes_value(true)
+             .requires("ECHO")
+             .validator(|b| echo::Binary::parse(&b).map(|_| ())))
+        .arg(Arg::with_name("ECHO_MAX_BYTES")
+             .long("echo-max-bytes")
+             .help("Truncate printed keys and values to this many bytes. Not with '--echo json', which is read back with '--from-file'.")
+             .takes_value(true)
+             .requires("ECHO")
+             .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("FROM_TIME")
+             .long("from-time")
+             .help("Skip the messages produced before this time. The time is given in RFC 3339 format or as milliseconds since the epoch.")
+             .takes_value(true)
+             .validator(|t| parse_time(&t).map(|_| ())))
+        .arg(Arg::with_name("UNTIL_TIME")
+             .long("until-time")
+             .help("Skip the messages produced at or after this time. The time is given in RFC 3339 format or as milliseconds since the epoch.")
+             .takes_value(true)
+             .conflicts_with("f")
+             .validator(|t| parse_time(&t).map(|_| ())))
+        .arg(Arg::with_name("PARTITION")
+             .short("p")
+             .long("partition")
+             .help("Only read this partition. Multiple partitions can be specified. An offset range can be appended like in '3:1200-5000'. The range includes both offsets, either of them may be omitted.")
+             .takes_value(true)
+             .multiple(true)
+             .validator(|p| parse_partition_spec(&p).map(|_| ())))
+        .arg(Arg::with_name("START_OFFSET")
+             .long("start-offset")
+             .help("The first offset to read from each partition, unless the partition has its own range.")
+             .takes_value(true)
+             // Otherwise a negative offset would be taken for an option.
+             .allow_hyphen_values(true)
+             .validator(|o| parse_offset(&o).map(|_| ())))
+        .arg(Arg::with_name("END_OFFSET")
+             .long("end-offset")
+             .help("The last offset to read from each partition, unless the partition has its own range.")
+             .takes_value(true)
+             // Otherwise a negative offset would be taken for an option.
+             .allow_hyphen_values(true)
+             .validator(|o| parse_offset(&o).map(|_| ())))
+        .arg(Arg::with_name("COLUMN")
+             .long("column")
+             .help("Add a column filled from the JSON value of the messages, like 'status=$.order.status:text'. The type is 'text', 'integer', 'real' or 'json' and defaults to 'text'. Multiple columns can be specified.")
+             .takes_value(true)
+             .multiple(true)
+             .number_of_values(1)
+             .validator(|c| Column::parse(&c).map(|_| ())))
+        .arg(Arg::with_name("INFER_SCHEMA")
+             .long("infer-schema")
+             .help("Add a column for each top level field of the JSON values. The columns and their types are inferred from the first messages of each topic."))
+        .arg(Arg::with_name("SAMPLE_SIZE")
+             .long("sample-size")
+             .help("The number of messages used to infer the columns of a topic. When no size is given 1000 is used.")
+             .takes_value(true)
+             .requires("INFER_SCHEMA")
+             .validator(|n| n.parse::<usize>().map(|_| ()).map_err(|e| e.to_string())))
+        .arg(Arg::with_name("KEY_FORMAT")
+             .long("key-format")
+             .help("The format of the message keys. The formats are the same as for '--value-format'. The type of the key column follows the format.")
+             .takes_value(true)
+             .validator(|f| decode::Format::parse(&f).map(|_| ())))
+        .arg(Arg::with_name("VALUE_FORMAT")
+             .long("value-format")
+             .help("The format of the message values: 'raw' stores the bytes, 'utf8' stores text, 'json' stores validated JSON, 'int64-be' stores a big endian 64 bit integer, 'avro' decodes Avro in the Confluent wire format, 'protobuf' decodes protobuf messages and 'msgpack' decodes MessagePack. Avro, protobuf and MessagePack are stored as JSON. Columns can be extracted from JSON values. When no format is given 'raw' is used.")
+             .takes_value(true)
+             .validator(|f| decode::Format::parse(&f).map(|_| ())))
+        .arg(Arg::with_name("SCHEMA_REGISTRY")
+             .long("schema-registry")
+             .help("The URL of a schema registry or a directory containing the Avro schemas as '<id>.avsc' files.")
+             .takes_value(true))
+        .arg(Arg::with_name("DESCRIPTOR_SET")
+             .long("descriptor-set")
+             .help("A protobuf descriptor set containing the message type, as written by 'protoc --include_imports --descriptor_set_out'.")
+             .takes_value(true))
+        .arg(Arg::with_name("KEY_MESSAGE_TYPE")
+             .long("key-message-type")
+             .help("The full name of the protobuf message type of the keys, like 'com.acme.OrderKey'.")
+             .takes_value(true))
+        .arg(Arg::with_name("MESSAGE_TYPE")
+             .long("message-type")
+             .help("The full name of the protobuf message type of the values, like 'com.acme.Order'.")
+             .takes_value(true))
+        .get_matches_from(argv);
 
+    let format = matches.value_of("FORMAT").map_or(sink::Format::Sql, |f| sink::Format::parse(f).unwrap());
     Args {
         brokers: match matches.values_of("BROKER") {
             Some(x) => x.map(|s| s.to_string()).collect(),
             None => vec!["localhost:9092".to_string()]
         },
-        topic: matches.value_of("TOPIC").unwrap_or("topic").to_string(),
-        output: matches.value_of("OUTPUT").unwrap_or("dump.sqlite").to_string(),
-        compact: matches.is_present("c")
+        from_file: matches.value_of("FROM_FILE").map(|s| s.to_string()),
+        topics: match matches.values_of("TOPIC") {
+            Some(x) => x.map(|s| s.to_string()).collect(),
+            None => vec![]
+        },
+        topic_pattern: matches.value_of("TOPIC_PATTERN").map(|s| s.to_string()),
+        output: matches.value_of("OUTPUT").unwrap_or(format.default_output()).to_string(),
+        format,
+        max_file_size: matches.value_of("MAX_FILE_SIZE").map(|n| n.parse().unwrap()),
+        binary_encoding: matches.value_of("BINARY_ENCODING").map(|e| sink::BinaryEncoding::parse(e).unwrap()),
+        compact: matches.is_present("c"),
+        follow: matches.is_present("f"),
+        resume: matches.is_present("r"),
+        overwrite: matches.is_present("OVERWRITE"),
+        progress: !matches.is_present("NO_PROGRESS"),
+        echo: matches.value_of("ECHO").map(|f| echo::Format::parse(f).unwrap()),
+        echo_binary: matches.value_of("ECHO_BINARY").map_or(echo::Binary::Lossy, |b| echo::Binary::parse(b).unwrap()),
+        echo_max_bytes: matches.value_of("ECHO_MAX_BYTES").map(|n| n.parse().unwrap()),
+        columns: match matches.values_of("COLUMN") {
+            Some(x) => x.map(|c| Column::parse(c).unwrap()).collect(),
+            None => vec![]
+        },
+        infer_schema: matches.is_present("INFER_SCHEMA"),
+        sample_size: matches.value_of("SAMPLE_SIZE").map_or(1_000, |n| n.parse().unwrap()),
+        key_format: matches.value_of("KEY_FORMAT").map_or(decode::Format::Raw, |f| decode::Format::parse(f).unwrap()),
+        value_format: matches.value_of("VALUE_FORMAT").map_or(decode::Format::Raw, |f| decode::Format::parse(f).unwrap()),
+        schema_registry: matches.value_of("SCHEMA_REGISTRY").map(|s| s.to_string()),
+        descriptor_set: matches.value_of("DESCRIPTOR_SET").map(|s| s.to_string()),
+        key_message_type: matches.value_of("KEY_MESSAGE_TYPE").map(|s| s.to_string()),
+        message_type: matches.value_of("MESSAGE_TYPE").map(|s| s.to_string()),
+        from_time: matches.value_of("FROM_TIME").map(|t| parse_time(t).unwrap()),
+        until_time: matches.value_of("UNTIL_TIME").map(|t| parse_time(t).unwrap()),
+        partitions: match matches.values_of("PARTITION") {
+            Some(x) => x.map(|p| parse_partition_spec(p).unwrap()).collect(),
+            None => vec![]
+        },
+        start_offset: matches.value_of("START_OFFSET").map(|o| o.parse().unwrap()),
+        end_offset: matches.value_of("END_OFFSET").map(|o| o.parse().unwrap())
     }
 }
 
-fn read_topic(args: Args, tx: SyncSender<MessageSets>) {
-    let mut c = Consumer::from_hosts(args.brokers, "dump-kafka-to-sql".to_string(), args.topic)
-        .with_fetch_max_wait_time(100)
-        .with_fetch_min_bytes(1_000)
-        .with_fetch_max_bytes_per_partition(100_000)
-        .with_fallback_offset(FetchOffset::Earliest)
-        .with_retry_max_bytes_limit(1_000_000)
-        .create().unwrap();
-    loop {
-        let message_sets = c.poll().unwrap();
-        if message_sets.is_empty() {
-            break;
-        }
-        tx.send(message_sets).unwrap();
-        c.commit_consumed().unwrap();
+/// Returns the content of `columns` for a message value. All columns are
+/// NULL, when the value is not JSON.
+fn extract_columns(columns: &[Column], value: &Decoded) -> Vec<SqlValue> {
+    if columns.is_empty() {
+        return vec![];
+    }
+    match value.json() {
+        Some(json) => columns.iter().map(|c| c.extract(&json)).collect(),
+        None => columns.iter().map(|_| SqlValue::Null).collect()
     }
 }
 
-fn create_table(args: &Args, conn: &Connection) -> String {
-    let table_name = args.topic.replace(".", "_");
-    let constraint = if args.compact {
-        ", unique (key) on conflict replace"
-    } else {
-        ""
+/// The table of a topic while the topic is written.
+enum TableState {
+    /// The columns are still inferred from these messages.
+    Sampling(Vec<Record>),
+    /// The table exists with these columns.
+    Ready(Vec<Column>)
+}
+
+/// Creates the table of a topic with the columns inferred from its sampled
+/// messages and writes the messages into it. When resuming a dump, only the
+/// inferred columns which the table already has are used.
+fn finish_sampling(args: &Args,
+                   sink: &mut dyn Sink,
+                   decoders: &mut Decoders,
+                   topic: &str,
+                   state: &mut TableState) -> Result<()> {
+    let sample = match *state {
+        TableState::Sampling(ref mut sample) => mem::take(sample),
+        TableState::Ready(_) => return Ok(())
     };
-    conn.execute(
-        &format!(
-            "create table {} (partition integer, offset integer, key blob, value blob, primary key (partition, offset){})",
-            table_name,
-            constraint),
-        &[]).unwrap();
-    table_name
-}
-
-fn save_data(args: Args, rx: Receiver<MessageSets>) {
-    let path = Path::new(&args.output);
-    remove_file(path).is_ok();
-    let conn = Connection::open(path).unwrap();
-    let table_name = create_table(&args, &conn);
-    let transaction = conn.transaction().unwrap();
-    let mut insert = conn.prepare(&format!("insert into {}(partition, offset, key, value) values(?, ?, ?, ?)", table_name)).unwrap();
-    let mut delete = conn.prepare(&format!("delete from {} where key = ?", table_name)).unwrap();
+    let mut values = vec![];
+    for r in &sample {
+        if let Some(json) = decoders.value.decode(&r.value)?.json() {
+            values.push(json.into_owned());
+        }
+    }
+    let mut inferred = infer_columns(&values, &args.columns);
+    if args.resume {
+        if let Some(existing) = sink.table_columns(topic)? {
+            inferred.retain(|c| existing.contains(&c.name));
+        }
+    }
+    let mut columns = args.columns.clone();
+    columns.extend(inferred);
+    sink.begin(topic, &columns)?;
+    write_records(args, sink, &columns, decoders, topic, &sample)?;
+    *state = TableState::Ready(columns);
+    Ok(())
+}
+
+/// Writes records of `topic` in batches. In compact mode a record without a
+/// value is a tombstone, which ends the current batch.
+fn write_records(args: &Args,
+                 sink: &mut dyn Sink,
+                 columns: &[Column],
+                 decoders: &mut Decoders,
+                 topic: &str,
+                 records: &[Record]) -> Result<()> {
+    let mut rows = vec![];
+    for r in records {
+        let key = decoders.key.decode(&r.key)?.to_sql();
+        if args.compact && r.value.is_empty() {
+            if !rows.is_empty() {
+                sink.write_batch(topic, mem::take(&mut rows))?;
+            }
+            sink.tombstone(topic, key)?;
+        } else {
+            let value = decoders.value.decode(&r.value)?;
+            rows.push(Row {
+                partition: r.partition,
+                offset: r.offset,
+                key,
+                value: value.to_sql(),
+                timestamp: r.timestamp,
+                timestamp_type: r.timestamp_type.map(|t| t.name()),
+                columns: extract_columns(columns, &value),
+                headers: &r.headers
+            });
+        }
+    }
+    if !rows.is_empty() {
+        sink.write_batch(topic, rows)?;
+    }
+    Ok(())
+}
+
+/// Writes the records received from the readers until all readers are done.
+/// The data is only committed, when all readers succeeded.
+fn save_data(args: Args,
+             decoders: Decoders,
+             progress: Arc<Mutex<Progress>>,
+             rx: Receiver<Vec<Record>>,
+             readers: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    sink::with_sink(&args, |sink| write_dump(&args, sink, decoders, progress, rx, readers))
+}
+
+/// Joins the readers which stopped, so the error of a reader ends the dump
+/// right away, while the others are still reading.
+fn join_finished(readers: &mut Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    let (finished, running): (Vec<_>, Vec<_>) = mem::take(readers).into_iter().partition(|r| r.is_finished());
+    *readers = running;
+    for reader in finished {
+        reader.join().expect("A reader thread panicked")?;
+    }
+    Ok(())
+}
+
+/// Drains `rx` into `sink`. The sink is finished, when all readers
+/// succeeded.
+fn write_dump(args: &Args,
+              sink: &mut dyn Sink,
+              mut decoders: Decoders,
+              progress: Arc<Mutex<Progress>>,
+              rx: Receiver<Vec<Record>>,
+              mut readers: Vec<JoinHandle<Result<()>>>) -> Result<()> {
+    let mut tables: HashMap<String, TableState> = HashMap::new();
+    for topic in &args.topics {
+        let state = if args.infer_schema {
+            TableState::Sampling(vec![])
+        } else {
+            sink.begin(topic, &args.columns)?;
+            TableState::Ready(args.columns.clone())
+        };
+        tables.insert(topic.clone(), state);
+    }
+    let commit_interval = Duration::from_millis(COMMIT_INTERVAL_MS);
+    let mut last_commit = Instant::now();
+    let mut echo = args.echo.clone().map(|format| Echo::new(format, args.echo_binary, args.echo_max_bytes));
     loop {
-        match rx.recv() {
-            Ok(message_sets) => {
-                for ms in message_sets.iter() {
-                    for m in ms.messages() {
-                        let s = String::from_utf8_lossy(m.value);
-                        println!("{} {} {} {}", ms.topic(), ms.partition(), m.offset, s);
-                        if args.compact && m.value.len() == 0 {
-                            delete.execute(&[&m.key]).unwrap();
-                        } else {
-                            insert.execute(&[&ms.partition(), &m.offset, &m.key, &m.value]).unwrap();
-                        }
+        match rx.recv_timeout(commit_interval) {
+            Ok(records) => {
+                for r in &records {
+                    if let Some(ref mut echo) = echo {
+                        echo.write(r)?;
                     }
+                    progress.lock().unwrap().record(&r.topic, r.partition, r.offset, r.key.len() + r.value.len());
+                }
+                // Each batch comes from the reader of a single topic.
+                let topic = records[0].topic.clone();
+                match *tables.get_mut(&topic).unwrap() {
+                    TableState::Ready(ref columns) => write_records(args, sink, columns, &mut decoders, &topic, &records)?,
+                    TableState::Sampling(ref mut sample) => sample.extend(records)
                 }
             }
-            Err(_) => break
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break
+        }
+        join_finished(&mut readers)?;
+        if let Some(ref mut echo) = echo {
+            echo.flush()?;
+        }
+        if args.progress {
+            progress.lock().unwrap().report();
         }
+        let commit = args.follow && last_commit.elapsed() >= commit_interval;
+        for (topic, state) in tables.iter_mut() {
+            // In follow mode a topic may never reach the sample size, so the
+            // columns are inferred from the messages read until the commit.
+            let complete = match *state {
+                TableState::Sampling(ref sample) => sample.len() >= args.sample_size || (commit && !sample.is_empty()),
+                TableState::Ready(_) => false
+            };
+            if complete {
+                finish_sampling(args, sink, &mut decoders, topic, state)?;
+            }
+        }
+        if commit {
+            sink.checkpoint()?;
+            last_commit = Instant::now();
+        }
+    }
+    for reader in readers {
+        reader.join().expect("A reader thread panicked")?;
     }
-    transaction.commit().unwrap();
+    for (topic, state) in tables.iter_mut() {
+        finish_sampling(args, sink, &mut decoders, topic, state)?;
+    }
+    sink.finish()?;
+    if args.progress {
+        progress.lock().unwrap().finish();
+    }
+    Ok(())
 }
 
-fn main() {
-    let args = parse_args();
-    let args1 = args.clone();
-    let args2 = args.clone();
+/// Fails, when two topics would be stored in the same table, like
+/// `orders.v1` and `orders-v1` or `orders.headers` and the headers of
+/// `orders`. Most databases ignore the case of table names, so `Orders` and
+/// `orders` collide as well.
+fn check_table_names(topics: &[String]) -> Result<()> {
+    let mut tables: HashMap<String, &str> = HashMap::new();
+    for topic in topics {
+        for table in &[sink::table_name(topic), sink::headers_table_name(topic)] {
+            if let Some(other) = tables.insert(table.to_lowercase(), topic) {
+                return Err(Error::Args(format!(
+                    "The topics '{}' and '{}' would both be stored in the table '{}'.", other, topic, table)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Dumps the topics of `source` as configured by `args`.
+fn dump(mut args: Args, source: Arc<dyn Source>) -> Result<()> {
+    if !args.format.updatable() && (args.compact || args.resume) {
+        return Err(Error::Args(format!(
+            "A dump in the format '{}' can neither be compacted nor resumed.", args.format.name())));
+    }
+    if args.max_file_size.is_some() && args.format == sink::Format::Sql {
+        return Err(Error::Args("--max-file-size only works with the formats 'parquet', 'csv' and 'jsonl'.".to_string()));
+    }
+    if let (Some(start), Some(end)) = (args.start_offset, args.end_offset) {
+        if start > end {
+            return Err(Error::Args("--start-offset is after --end-offset.".to_string()));
+        }
+    }
+    if args.follow && args.format == sink::Format::Parquet && args.max_file_size.is_none() {
+        // A file can only be read once it is closed, which a dump which
+        // never ends only does when rolling it.
+        return Err(Error::Args("In follow mode the format 'parquet' needs --max-file-size.".to_string()));
+    }
+    if args.binary_encoding.is_some() && args.format != sink::Format::Csv && args.format != sink::Format::Jsonl {
+        return Err(Error::Args("--binary-encoding only works with the formats 'csv' and 'jsonl'.".to_string()));
+    }
+    check_columns(&args.columns).map_err(Error::Args)?;
+    if args.echo_max_bytes.is_some() && matches!(args.echo, Some(echo::Format::Json)) {
+        // Truncated messages would be read back by --from-file as they are.
+        return Err(Error::Args("--echo-max-bytes does not work with '--echo json'.".to_string()));
+    }
+    args.topics = source::resolve_topics(&args, &*source)?;
+    if args.topics.is_empty() {
+        return Err(Error::Args("No topic matches the given pattern.".to_string()));
+    }
+    check_table_names(&args.topics)?;
+    if !args.resume && !args.overwrite && sink::exists(&args)? {
+        return Err(Error::Args(format!(
+            "'{}' already contains a dump. Use --overwrite to replace it or --resume to continue it.", args.output)));
+    }
+    let schema_registry = args.schema_registry.as_deref();
+    let descriptor_set = args.descriptor_set.as_deref();
+    let decoders = Decoders {
+        key: Decoder::new(args.key_format,
+                          schema_registry,
+                          descriptor_set,
+                          args.key_message_type.as_deref())?,
+        value: Decoder::new(args.value_format,
+                            schema_registry,
+                            descriptor_set,
+                            args.message_type.as_deref())?
+    };
+    let progress = Arc::new(Mutex::new(Progress::new(io::stderr().is_terminal())));
     let (tx, rx) = sync_channel(10);
-    thread::spawn(move|| read_topic(args1, tx));
-    let save_thread = thread::spawn(move|| save_data(args2, rx));
-    save_thread.join().unwrap();
+    let mut readers = vec![];
+    for topic in &args.topics {
+        let args1 = args.clone();
+        let resume = if args.resume {
+            sink::stored_offsets(&args, topic)?
+        } else {
+            HashMap::new()
+        };
+        let source = source.clone();
+        let progress = progress.clone();
+        let tx = tx.clone();
+        let topic = topic.clone();
+        readers.push(thread::spawn(move|| source.read_topic(&args1, &topic, &resume, &progress, &tx)));
+    }
+    drop(tx);
+    let save_thread = thread::spawn(move|| save_data(args, decoders, progress, rx, readers));
+    save_thread.join().expect("The writer thread panicked")
+}
+
+fn run(args: Args) -> Result<()> {
+    let source = source::open(&args)?;
+    dump(args, source)
+}
+
+fn main() {
+    let args = parse_args(env::args_os());
+    if let Err(e) = run(args) {
+        eprintln!("Error: {}", e);
+        exit(e.exit_code());
+    }
+}
+
+/// End-to-end tests of dumps and the helpers the tests of the sources,
+/// sinks and decoders share.
+#[cfg(test)]
+pub mod tests {
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::{Receiver, SyncSender, channel};
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use rusqlite::Connection;
+    use tempfile::TempDir;
+    use error::{Error, Result};
+    use progress::Progress;
+    use source::{MemorySource, Record, Source, TimestampType};
+    use {Args, dump, parse_args, parse_partition_spec};
+
+    /// A message of the topic `orders`, which was produced a second after
+    /// the previous offset.
+    pub fn record(partition: i32, offset: i64, key: &str, value: &str) -> Record {
+        Record {
+            topic: "orders".to_string(),
+            partition,
+            offset,
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+            timestamp: Some(1_700_000_000_000 + offset * 1_000),
+            timestamp_type: Some(TimestampType::CreateTime),
+            headers: vec![]
+        }
+    }
+
+    /// Dumps the topic `orders` of `records` into `output`.
+    pub fn dump_to(output: &str, records: Vec<Record>, options: &[&str]) -> Result<()> {
+        let mut argv = vec!["dump-kafka-to-sql", "--no-progress", "-t", "orders", "-o", output];
+        argv.extend(options);
+        dump(parse_args(argv), Arc::new(MemorySource::new(records)))
+    }
+
+    /// Dumps the topic `orders` of `records` into a SQLite file in `dir`.
+    pub fn run_dump(dir: &TempDir, records: Vec<Record>, options: &[&str]) -> Result<()> {
+        dump_to(dir.path().join("dump.sqlite").to_str().unwrap(), records, options)
+    }
+
+    /// Runs a query on the dump in `dir`, which returns a single text column.
+    pub fn query(dir: &TempDir, sql: &str) -> Vec<String> {
+        let conn = Connection::open(dir.path().join("dump.sqlite")).unwrap();
+        let mut stmt = conn.prepare(sql).unwrap();
+        let rows: Vec<String> = stmt.query_map(&[], |row| row.get::<String>(0)).unwrap().map(|r| r.unwrap()).collect();
+        rows
+    }
+
+    #[test]
+    fn partition_range() {
+        let dir = TempDir::new().unwrap();
+        let mut records: Vec<Record> = (0..10).map(|o| record(0, o, "k", "v")).collect();
+        records.extend((1_000..6_000).map(|o| record(3, o, "k", "v")));
+        run_dump(&dir, records, &["--partition", "3:1200-5000"]).unwrap();
+        assert_eq!(query(&dir, "select partition || ' ' || count(*) || ' ' || min(offset) || ' ' || max(offset) from orders group by partition"),
+                   vec!["3 3801 1200 5000"]);
+    }
+
+    #[test]
+    fn several_topics() {
+        let dir = TempDir::new().unwrap();
+        let mut records: Vec<Record> = (0..3).map(|o| record(0, o, "k", "v")).collect();
+        records.extend((0..5).map(|o| Record { topic: "payments".to_string(), ..record((o % 2) as i32, o, "k", "v") }));
+        run_dump(&dir, records, &["-t", "payments"]).unwrap();
+        assert_eq!(query(&dir, "select name from sqlite_master where type = 'table' order by name"),
+                   vec!["orders", "orders_headers", "payments", "payments_headers"]);
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["3"]);
+        assert_eq!(query(&dir, "select count(*) || '' from payments"), vec!["5"]);
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert_eq!(parse_partition_spec("3:1200-").unwrap().start, Some(1200));
+        assert_eq!(parse_partition_spec("3:1-1").unwrap().end, Some(1));
+        assert_eq!(parse_partition_spec("0:3-1").err().unwrap(), "The range of '0:3-1' ends before it starts");
+        assert!(parse_partition_spec("0:-1-3").err().unwrap().contains("is not a partition"));
+        let dir = TempDir::new().unwrap();
+        match run_dump(&dir, vec![record(0, 0, "k", "v")], &["--start-offset", "3", "--end-offset", "1"]) {
+            Err(Error::Args(msg)) => assert_eq!(msg, "--start-offset is after --end-offset."),
+            result => panic!("{:?}", result)
+        }
+    }
+
+    #[test]
+    fn truncated_json_echo() {
+        let dir = TempDir::new().unwrap();
+        match run_dump(&dir, vec![record(0, 0, "k", "v")], &["--echo", "json", "--echo-max-bytes", "4"]) {
+            Err(Error::Args(msg)) => assert_eq!(msg, "--echo-max-bytes does not work with '--echo json'."),
+            result => panic!("{:?}", result)
+        }
+        assert!(!dir.path().join("dump.sqlite").exists());
+    }
+
+    #[test]
+    fn table_name_collision() {
+        let dir = TempDir::new().unwrap();
+        let mut records = vec![record(0, 0, "k", "v"), record(0, 7, "k", "v")];
+        records[0].topic = "orders.v1".to_string();
+        records[1].topic = "orders-v1".to_string();
+        match run_dump(&dir, records, &["--topic-pattern", "orders.v1"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("'orders-v1' and 'orders.v1'"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        let mut r = record(0, 0, "k", "v");
+        r.topic = "orders.headers".to_string();
+        match run_dump(&dir, vec![r], &["-t", "orders.headers"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("table 'orders_headers'"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        let mut records = vec![record(0, 0, "k", "v"), record(0, 1, "k", "v")];
+        records[1].topic = "Orders".to_string();
+        match run_dump(&dir, records, &["-t", "Orders"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("'orders' and 'Orders'"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        assert!(!dir.path().join("dump.sqlite").exists());
+    }
+
+    #[test]
+    fn time_range() {
+        let dir = TempDir::new().unwrap();
+        let records = (0..10).map(|o| record(0, o, "k", "v")).collect();
+        run_dump(&dir, records, &["--from-time", "1700000003000", "--until-time", "2023-11-14T22:13:27Z"]).unwrap();
+        assert_eq!(query(&dir, "select offset || '' from orders order by offset"), vec!["3", "4", "5", "6"]);
+    }
+
+    #[test]
+    fn resume() {
+        let dir = TempDir::new().unwrap();
+        let records: Vec<Record> = (0..10).map(|o| record(0, o, "k", "v")).collect();
+        run_dump(&dir, records.clone(), &["--end-offset", "4"]).unwrap();
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["5"]);
+        match run_dump(&dir, records.clone(), &[]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("already contains a dump"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        run_dump(&dir, records.clone(), &["--resume", "--end-offset", "6"]).unwrap();
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["7"]);
+        // Stored messages are not read again, even when the start offset is
+        // given explicitly.
+        run_dump(&dir, records, &["--resume", "--start-offset", "0"]).unwrap();
+        let offsets: Vec<String> = (0..10).map(|o: i64| o.to_string()).collect();
+        assert_eq!(query(&dir, "select offset || '' from orders order by offset"), offsets);
+    }
+
+    #[test]
+    fn resume_from_time() {
+        let dir = TempDir::new().unwrap();
+        let records: Vec<Record> = (0..10).map(|o| record(0, o, "k", "v")).collect();
+        run_dump(&dir, records.clone(), &["--end-offset", "2"]).unwrap();
+        run_dump(&dir, records.clone(), &["--resume", "--from-time", "1700000005000"]).unwrap();
+        assert_eq!(query(&dir, "select offset || '' from orders order by offset"), vec!["0", "1", "2", "5", "6", "7", "8", "9"]);
+        // Messages after the stored ones are not read again.
+        run_dump(&dir, records, &["--resume", "--from-time", "1700000001000"]).unwrap();
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["8"]);
+    }
+
+    /// Sends its records as a single batch and then waits until it is
+    /// released, like Kafka in follow mode, when no new messages arrive.
+    struct WaitingSource {
+        records: Vec<Record>,
+        release: Mutex<Receiver<()>>
+    }
+
+    impl Source for WaitingSource {
+        fn topic_names(&self) -> Result<Vec<String>> {
+            Ok(vec!["orders".to_string()])
+        }
+
+        fn read_topic(&self,
+                      _args: &Args,
+                      _topic: &str,
+                      _resume: &HashMap<i32, i64>,
+                      _progress: &Mutex<Progress>,
+                      tx: &SyncSender<Vec<Record>>) -> Result<()> {
+            tx.send(self.records.clone()).unwrap();
+            let _ = self.release.lock().unwrap().recv();
+            Ok(())
+        }
+    }
+
+    /// The statuses committed into the dump at `output` so far.
+    fn committed_statuses(output: &str) -> Option<Vec<String>> {
+        let conn = Connection::open(output).ok()?;
+        let mut stmt = conn.prepare("select status from orders order by offset").ok()?;
+        let rows = stmt.query_map(&[], |row| row.get::<String>(0)).ok()?;
+        rows.collect::<::std::result::Result<Vec<String>, _>>().ok()
+    }
+
+    #[test]
+    fn follow() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump.sqlite").to_str().unwrap().to_string();
+        let (release, waiting) = channel();
+        let source = WaitingSource {
+            records: vec![record(0, 0, "a", "{\"status\": \"new\"}"), record(0, 1, "b", "{\"status\": \"paid\"}")],
+            release: Mutex::new(waiting)
+        };
+        let argv: Vec<String> = ["dump-kafka-to-sql", "--no-progress", "-t", "orders", "-o", &output,
+                                 "--follow", "--infer-schema", "--value-format", "json"].iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let writer = thread::spawn(move || dump(parse_args(argv), Arc::new(source)));
+        // The sample is far from complete, but the columns are inferred and
+        // the messages committed, while the reader is still waiting.
+        let started = Instant::now();
+        let statuses = loop {
+            // Opening the output before the dump would create it.
+            let statuses = if Path::new(&output).exists() { committed_statuses(&output) } else { None };
+            match statuses {
+                Some(ref statuses) if !statuses.is_empty() => break statuses.clone(),
+                _ if started.elapsed() > Duration::from_secs(10) => panic!("nothing was committed"),
+                _ => thread::sleep(Duration::from_millis(50))
+            }
+        };
+        assert_eq!(statuses, vec!["new", "paid"]);
+        assert!(!writer.is_finished());
+        release.send(()).unwrap();
+        writer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn compact() {
+        let dir = TempDir::new().unwrap();
+        let mut records = vec![record(0, 0, "a", "1"), record(0, 1, "b", "2"), record(0, 2, "a", ""), record(0, 3, "b", "3")];
+        for (r, trace) in records.iter_mut().zip(&["x", "y", "z", "w"]) {
+            r.headers.push(("trace".to_string(), Some(trace.as_bytes().to_vec())));
+        }
+        run_dump(&dir, records, &["--compact"]).unwrap();
+        assert_eq!(query(&dir, "select offset || ' ' || key || ' ' || value from orders"), vec!["3 b 3"]);
+        assert_eq!(query(&dir, "select offset || ' ' || name || ' ' || value from orders_headers"), vec!["3 trace w"]);
+    }
+
+    #[test]
+    fn headers() {
+        let dir = TempDir::new().unwrap();
+        let mut r = record(0, 0, "k", "v");
+        r.headers = vec![
+            ("trace".to_string(), Some(b"a1".to_vec())),
+            ("empty".to_string(), None),
+            ("trace".to_string(), Some(b"a2".to_vec()))
+        ];
+        run_dump(&dir, vec![r, record(0, 1, "k", "v")], &[]).unwrap();
+        assert_eq!(query(&dir, "select offset || ' ' || name || ' ' || coalesce(value, 'NULL') from orders_headers order by rowid"),
+                   vec!["0 trace a1", "0 empty NULL", "0 trace a2"]);
+    }
 }
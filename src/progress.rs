@@ -0,0 +1,213 @@
+//! Progress reporting on stderr.
+//!
+//! When stderr is a terminal, a table with one line per partition is redrawn
+//! a few times per second. Otherwise a summary line is logged periodically.
+
+use std::cmp::max;
+use std::collections::BTreeMap;
+use std::io::{stderr, Write};
+use std::time::{Duration, Instant};
+
+/// How often the table is redrawn on a terminal.
+const TTY_INTERVAL_MS: u64 = 200;
+
+/// How often a line is logged when stderr is not a terminal.
+const LOG_INTERVAL_MS: u64 = 10_000;
+
+struct PartitionProgress {
+    /// The offset after the last written message.
+    position: i64,
+    /// The offset after the last message to read. `None` in follow mode.
+    end: Option<i64>,
+    messages: u64,
+    bytes: u64
+}
+
+pub struct Progress {
+    partitions: BTreeMap<(String, i32), PartitionProgress>,
+    tty: bool,
+    started: Instant,
+    last_report: Instant,
+    /// The number of lines drawn by the last report on a terminal.
+    drawn_lines: usize
+}
+
+impl Progress {
+    pub fn new(tty: bool) -> Progress {
+        let now = Instant::now();
+        Progress {
+            partitions: BTreeMap::new(),
+            tty,
+            started: now,
+            last_report: now,
+            drawn_lines: 0
+        }
+    }
+
+    /// Registers a partition which is read from `start` up to `end`.
+    pub fn start_partition(&mut self, topic: &str, partition: i32, start: i64, end: Option<i64>) {
+        self.partitions.insert((topic.to_string(), partition), PartitionProgress {
+            position: start,
+            end,
+            messages: 0,
+            bytes: 0
+        });
+    }
+
+    /// Counts a written message.
+    pub fn record(&mut self, topic: &str, partition: i32, offset: i64, bytes: usize) {
+        if let Some(p) = self.partitions.get_mut(&(topic.to_string(), partition)) {
+            // The reader may have finished the partition already.
+            p.position = max(p.position, offset + 1);
+            p.messages += 1;
+            p.bytes += bytes as u64;
+        }
+    }
+
+    /// Moves a partition to its end, when the reader is done with it. The
+    /// last offsets may have no messages, like the gaps of compacted topics
+    /// or transaction markers.
+    pub fn finish_partition(&mut self, topic: &str, partition: i32) {
+        if let Some(p) = self.partitions.get_mut(&(topic.to_string(), partition)) {
+            if let Some(end) = p.end {
+                p.position = end;
+            }
+        }
+    }
+
+    /// Prints the progress, if the last report is long enough ago.
+    pub fn report(&mut self) {
+        let interval = Duration::from_millis(if self.tty { TTY_INTERVAL_MS } else { LOG_INTERVAL_MS });
+        if self.last_report.elapsed() >= interval {
+            self.print();
+        }
+    }
+
+    /// Prints the final progress.
+    pub fn finish(&mut self) {
+        self.print();
+    }
+
+    fn print(&mut self) {
+        self.last_report = Instant::now();
+        let elapsed = self.started.elapsed();
+        let total = self.summary(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9);
+
+        let stderr = stderr();
+        let mut out = stderr.lock();
+        if self.tty {
+            if self.drawn_lines > 0 {
+                let _ = write!(out, "\x1b[{}A", self.drawn_lines);
+            }
+            for (&(ref topic, partition), p) in &self.partitions {
+                let target = match p.end {
+                    Some(end) => format!("offset {} of {}", p.position, end),
+                    None => format!("offset {}", p.position)
+                };
+                let _ = writeln!(out, "\x1b[2K{}/{}: {}, {} messages, {}",
+                                 topic, partition, target, p.messages, format_bytes(p.bytes));
+            }
+            let _ = writeln!(out, "\x1b[2K{}", total);
+            self.drawn_lines = self.partitions.len() + 1;
+        } else {
+            let _ = writeln!(out, "{}", total);
+        }
+    }
+
+    /// The line with the totals of all partitions after `seconds`. It only
+    /// has an ETA, when every partition has an end.
+    fn summary(&self, seconds: f64) -> String {
+        let messages: u64 = self.partitions.values().map(|p| p.messages).sum();
+        let bytes: u64 = self.partitions.values().map(|p| p.bytes).sum();
+        let rate = if seconds > 0.0 { messages as f64 / seconds } else { 0.0 };
+        let mut total = format!("{} messages, {}, {:.0} messages/s",
+                                messages, format_bytes(bytes), rate);
+        // Offsets are only an estimate for the number of messages, as
+        // compacted topics have gaps.
+        let remaining: Option<i64> = self.partitions.values()
+            .map(|p| p.end.map(|end| end - p.position))
+            .sum();
+        if let Some(remaining) = remaining {
+            if rate > 0.0 {
+                total.push_str(&format!(", ETA {}", format_duration(remaining as f64 / rate)));
+            }
+        }
+        total
+    }
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, units[unit])
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Progress, format_bytes, format_duration};
+
+    #[test]
+    fn bytes() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(5 * 1024 * 1024 * 1024), "5.0 GiB");
+        assert_eq!(format_bytes(2048 * 1024 * 1024 * 1024 * 1024), "2048.0 TiB");
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(format_duration(0.4), "0:00:00");
+        assert_eq!(format_duration(59.6), "0:01:00");
+        assert_eq!(format_duration(3725.0), "1:02:05");
+        assert_eq!(format_duration(100.0 * 3600.0), "100:00:00");
+    }
+
+    #[test]
+    fn eta() {
+        let mut progress = Progress::new(false);
+        progress.start_partition("orders", 0, 0, Some(100));
+        progress.start_partition("orders", 1, 50, Some(80));
+        assert_eq!(progress.summary(10.0), "0 messages, 0 B, 0 messages/s");
+        for offset in 0..10 {
+            progress.record("orders", 0, offset, 50);
+        }
+        for offset in 50..60 {
+            progress.record("orders", 1, offset, 50);
+        }
+        // 110 offsets are left at 2 messages per second.
+        assert_eq!(progress.summary(10.0), "20 messages, 1000 B, 2 messages/s, ETA 0:00:55");
+
+        // A followed partition has no end, so there is no ETA.
+        progress.start_partition("payments", 0, 0, None);
+        progress.record("payments", 0, 0, 24);
+        assert_eq!(progress.summary(10.0), "21 messages, 1.0 KiB, 2 messages/s");
+    }
+
+    #[test]
+    fn finished_partition() {
+        let mut progress = Progress::new(false);
+        progress.start_partition("orders", 0, 0, Some(100));
+        progress.record("orders", 0, 9, 50);
+        // The last offsets are transaction markers.
+        progress.finish_partition("orders", 0);
+        assert_eq!(progress.summary(10.0), "1 messages, 50 B, 0 messages/s, ETA 0:00:00");
+        // A message still on its way to the writer.
+        progress.record("orders", 0, 10, 50);
+        assert_eq!(progress.partitions.values().next().unwrap().position, 100);
+    }
+}
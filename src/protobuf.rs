@@ -0,0 +1,76 @@
+//! Decoding of protobuf values with a descriptor set.
+//!
+//! A descriptor set is created with `protoc --include_imports
+//! --descriptor_set_out=foo.desc foo.proto`.
+
+use std::fs::File;
+use std::io::Read;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor};
+use serde_json;
+use error::{Error, Result};
+
+pub struct ProtobufDecoder {
+    descriptor: MessageDescriptor
+}
+
+impl ProtobufDecoder {
+    /// `message_type` is the full name of the message, like
+    /// `com.acme.Order`.
+    pub fn new(descriptor_set: &str, message_type: &str) -> Result<ProtobufDecoder> {
+        let mut bytes = vec![];
+        File::open(descriptor_set)?.read_to_end(&mut bytes)?;
+        let pool = DescriptorPool::decode(&bytes[..])
+            .map_err(|e| Error::Args(format!("'{}' is not a descriptor set: {}", descriptor_set, e)))?;
+        let descriptor = pool.get_message_by_name(message_type)
+            .ok_or_else(|| Error::Args(format!("'{}' does not contain the message type {}", descriptor_set, message_type)))?;
+        Ok(ProtobufDecoder {
+            descriptor
+        })
+    }
+
+    /// Decodes a value into JSON, using the protobuf JSON mapping.
+    pub fn decode(&self, bytes: &[u8]) -> Result<serde_json::Value> {
+        let message = DynamicMessage::decode(self.descriptor.clone(), bytes)
+            .map_err(|e| Error::Decode(format!("{}: {}", self.descriptor.full_name(), e)))?;
+        serde_json::to_value(&message)
+            .map_err(|e| Error::Decode(format!("{}: {}", self.descriptor.full_name(), e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use error::Error;
+    use decode::{Decoded, Decoder, Format};
+    use super::ProtobufDecoder;
+
+    const DESCRIPTOR_SET: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/order.desc");
+
+    #[test]
+    fn decode() {
+        let decoder = ProtobufDecoder::new(DESCRIPTOR_SET, "acme.Order").unwrap();
+        // id = 42, status = "new"
+        assert_eq!(decoder.decode(&[0x08, 0x2a, 0x12, 0x03, b'n', b'e', b'w']).unwrap(), json!({"id": "42", "status": "new"}));
+        match decoder.decode(&[0x12, 0x05, b'n']) {
+            Err(Error::Decode(msg)) => assert!(msg.starts_with("acme.Order: "), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+    }
+
+    #[test]
+    fn empty_message() {
+        let mut decoder = Decoder::new(Format::Protobuf, None, Some(DESCRIPTOR_SET), Some("acme.Order")).unwrap();
+        match decoder.decode(&[]) {
+            Ok(Decoded::Json(json)) => assert_eq!(json, json!({})),
+            _ => panic!("not decoded as JSON")
+        }
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        match ProtobufDecoder::new(DESCRIPTOR_SET, "acme.Invoice") {
+            Err(Error::Args(msg)) => assert!(msg.ends_with("does not contain the message type acme.Invoice"), "{}", msg),
+            Err(e) => panic!("{}", e),
+            Ok(_) => panic!("found acme.Invoice")
+        }
+    }
+}
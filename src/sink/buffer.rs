@@ -0,0 +1,46 @@
+//! Buffering the messages of a topic, which the DuckDB, PostgreSQL and
+//! MySQL sinks write in bulk.
+
+use std::collections::HashMap;
+use std::mem;
+use value::SqlValue;
+
+/// The messages of a topic which are buffered to be written together. In
+/// compact mode a message replaces the buffered message with the same key,
+/// so only the last one is written.
+pub struct Buffer<T> {
+    compact: bool,
+    /// Messages which were replaced are `None`.
+    messages: Vec<Option<T>>,
+    /// The index of the buffered message with each key, in compact mode.
+    keys: HashMap<String, usize>
+}
+
+impl<T> Buffer<T> {
+    pub fn new(compact: bool) -> Buffer<T> {
+        Buffer {
+            compact,
+            messages: vec![],
+            keys: HashMap::new()
+        }
+    }
+
+    /// Adds a message with `key` and returns the number of buffered
+    /// messages.
+    pub fn push(&mut self, key: &SqlValue, message: T) -> usize {
+        // NULL keys never conflict.
+        if self.compact && *key != SqlValue::Null {
+            if let Some(i) = self.keys.insert(format!("{:?}", key), self.messages.len()) {
+                self.messages[i] = None;
+            }
+        }
+        self.messages.push(Some(message));
+        self.messages.len()
+    }
+
+    /// Removes all messages and returns those which were not replaced.
+    pub fn take(&mut self) -> Vec<T> {
+        self.keys.clear();
+        mem::take(&mut self.messages).into_iter().flatten().collect()
+    }
+}
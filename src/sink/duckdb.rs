@@ -0,0 +1,267 @@
+//! Writing a dump into a DuckDB file.
+//!
+//! The messages are loaded with appenders. DuckDB checks unique constraints
+//! before deletes in the same transaction are applied, so the tables have no
+//! unique key in compact mode. Instead the messages are buffered, and the
+//! messages with the same keys are deleted before the buffer is appended.
+
+use std::collections::HashMap;
+use duckdb::{Appender, Connection, appender_params_from_iter, params_from_iter};
+use duckdb::types::Value;
+use columns::Column;
+use error::Result;
+use value::{SqlType, SqlValue};
+use Args;
+use super::buffer::Buffer;
+use super::{Row, Sink, file_exists, headers_table_name, quote, table_name};
+
+/// The number of buffered messages of a topic at which they are written in
+/// compact mode.
+const FLUSH_ROWS: usize = 10_000;
+
+/// The maximum number of keys deleted by a single statement.
+const DELETE_KEYS: usize = 1_000;
+
+fn type_name(sql_type: SqlType) -> &'static str {
+    match sql_type {
+        SqlType::Integer => "bigint",
+        SqlType::Real => "double",
+        // The json type needs an extension.
+        SqlType::Text | SqlType::Json => "varchar",
+        SqlType::Blob => "blob"
+    }
+}
+
+fn to_value(value: &SqlValue) -> Value {
+    match *value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => Value::BigInt(i),
+        SqlValue::Real(r) => Value::Double(r),
+        SqlValue::Text(ref s) => Value::Text(s.clone()),
+        SqlValue::Blob(ref b) => Value::Blob(b.clone())
+    }
+}
+
+pub fn stored_offsets(args: &Args, topic: &str) -> Result<HashMap<i32, i64>> {
+    if !file_exists(args) {
+        return Ok(HashMap::new());
+    }
+    let conn = Connection::open(&args.output)?;
+    let table_name = table_name(topic);
+    let exists: i64 = conn.query_row(
+        "select count(*) from information_schema.tables where table_name = ?",
+        [&table_name],
+        |row| row.get(0))?;
+    if exists == 0 {
+        return Ok(HashMap::new());
+    }
+    let mut stmt = conn.prepare(&format!("select partition, max(\"offset\") + 1 from {} group by partition", quote(&table_name)))?;
+    let mut offsets = HashMap::new();
+    for row in stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))? {
+        let (partition, offset) = row?;
+        offsets.insert(partition, offset);
+    }
+    Ok(offsets)
+}
+
+pub fn write<F>(args: &Args, path: &str, f: F) -> Result<()>
+    where F: FnOnce(&mut dyn Sink) -> Result<()>
+{
+    let conn = Connection::open(path)?;
+    conn.execute_batch("begin")?;
+    {
+        let mut sink = DuckdbSink {
+            args,
+            conn: &conn,
+            tables: HashMap::new()
+        };
+        f(&mut sink)?;
+    }
+    conn.execute_batch("commit")?;
+    Ok(())
+}
+
+/// A buffered message in compact mode.
+struct Pending {
+    row: Vec<Value>,
+    headers: Vec<Vec<Value>>,
+    key: Value,
+    deleted: bool
+}
+
+struct Table<'conn> {
+    rows: Appender<'conn>,
+    headers: Appender<'conn>,
+    pending: Buffer<Pending>
+}
+
+struct DuckdbSink<'a, 'conn> {
+    args: &'a Args,
+    conn: &'conn Connection,
+    tables: HashMap<String, Table<'conn>>
+}
+
+impl<'a, 'conn> DuckdbSink<'a, 'conn> {
+    fn buffer(&mut self, topic: &str, key: &SqlValue, pending: Pending) -> Result<()> {
+        if self.tables.get_mut(topic).unwrap().pending.push(key, pending) >= FLUSH_ROWS {
+            self.flush_table(topic)?;
+        }
+        Ok(())
+    }
+
+    fn flush_table(&mut self, topic: &str) -> Result<()> {
+        let table = self.tables.get_mut(topic).unwrap();
+        let pending = table.pending.take();
+        let keys: Vec<Value> = pending.iter().map(|p| p.key.clone()).filter(|k| *k != Value::Null).collect();
+        for chunk in keys.chunks(DELETE_KEYS) {
+            let placeholders = vec!["?"; chunk.len()].join(", ");
+            self.conn.execute(
+                &format!(
+                    "delete from {0} using {1} where {1}.key in ({2}) and {0}.partition = {1}.partition and {0}.\"offset\" = {1}.\"offset\"",
+                    quote(&headers_table_name(topic)),
+                    quote(&table_name(topic)),
+                    placeholders),
+                params_from_iter(chunk))?;
+            self.conn.execute(
+                &format!("delete from {} where key in ({})", quote(&table_name(topic)), placeholders),
+                params_from_iter(chunk))?;
+        }
+        for p in pending.into_iter().filter(|p| !p.deleted) {
+            table.rows.append_row(appender_params_from_iter(p.row))?;
+            for header in p.headers {
+                table.headers.append_row(appender_params_from_iter(header))?;
+            }
+        }
+        table.rows.flush()?;
+        table.headers.flush()?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let topics: Vec<String> = self.tables.keys().cloned().collect();
+        for topic in topics {
+            self.flush_table(&topic)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'a, 'conn> Sink for DuckdbSink<'a, 'conn> {
+    fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()> {
+        let table_name = table_name(topic);
+        let definitions: String = columns.iter()
+            .map(|c| format!(", {} {}", quote(&c.name), type_name(c.column_type.sql_type())))
+            .collect();
+        let headers_table_name = headers_table_name(topic);
+        self.conn.execute_batch(&format!(
+            "create table if not exists {} (partition integer, \"offset\" bigint, key {}, value {}, timestamp bigint, timestamp_type varchar{}, primary key (partition, \"offset\"));
+             create table if not exists {} (partition integer, \"offset\" bigint, name varchar, value blob);",
+            quote(&table_name),
+            type_name(self.args.key_format.sql_type()),
+            type_name(self.args.value_format.sql_type()),
+            definitions,
+            quote(&headers_table_name)))?;
+        // Appenders take the plain names.
+        self.tables.insert(topic.to_string(), Table {
+            rows: self.conn.appender(&table_name)?,
+            headers: self.conn.appender(&headers_table_name)?,
+            pending: Buffer::new(true)
+        });
+        Ok(())
+    }
+
+    fn table_columns(&mut self, topic: &str) -> Result<Option<Vec<String>>> {
+        let mut stmt = self.conn.prepare(
+            "select column_name from information_schema.columns where table_name = ? order by ordinal_position")?;
+        let mut names = vec![];
+        for name in stmt.query_map([&table_name(topic)], |row| row.get(0))? {
+            names.push(name?);
+        }
+        Ok(if names.is_empty() { None } else { Some(names) })
+    }
+
+    fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()> {
+        for row in rows {
+            let values: Vec<Value> = row.values().iter().map(to_value).collect();
+            let headers: Vec<Vec<Value>> = row.headers.iter()
+                .map(|(name, value)| vec![
+                    Value::Int(row.partition),
+                    Value::BigInt(row.offset),
+                    Value::Text(name.clone()),
+                    value.clone().map_or(Value::Null, Value::Blob)
+                ])
+                .collect();
+            if self.args.compact {
+                self.buffer(topic, &row.key, Pending {
+                    row: values,
+                    headers,
+                    key: to_value(&row.key),
+                    deleted: false
+                })?;
+                continue;
+            }
+            let table = self.tables.get_mut(topic).unwrap();
+            table.rows.append_row(appender_params_from_iter(values))?;
+            for header in headers {
+                table.headers.append_row(appender_params_from_iter(header))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tombstone(&mut self, topic: &str, key: SqlValue) -> Result<()> {
+        self.buffer(topic, &key, Pending {
+            row: vec![],
+            headers: vec![],
+            key: to_value(&key),
+            deleted: true
+        })
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.flush()?;
+        self.conn.execute_batch("commit; begin")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use duckdb::Connection;
+    use tempfile::TempDir;
+    use tests::{dump_to, record};
+
+    #[test]
+    fn compact() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump.duckdb");
+        let output = output.to_str().unwrap();
+        let mut records = vec![record(0, 0, "a", "1"), record(0, 1, "b", "2"), record(0, 2, "a", ""),
+                               record(0, 3, "b", "3"), record(0, 4, "c", "4"), record(0, 5, "c", "5")];
+        for (r, trace) in records.iter_mut().zip(&["x", "y", "z", "w", "v", "u"]) {
+            r.headers.push(("trace".to_string(), Some(trace.as_bytes().to_vec())));
+        }
+        dump_to(output, records.clone(), &["--compact", "--end-offset", "1"]).unwrap();
+        // The stored messages are replaced and deleted, as well as the
+        // buffered ones.
+        dump_to(output, records, &["--compact", "--resume"]).unwrap();
+
+        let conn = Connection::open(output).unwrap();
+        let mut stmt = conn.prepare("select \"offset\", key, value from orders order by \"offset\"").unwrap();
+        let rows: Vec<(i64, Vec<u8>, Vec<u8>)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(rows, vec![(3, b"b".to_vec(), b"3".to_vec()), (5, b"c".to_vec(), b"5".to_vec())]);
+        let mut stmt = conn.prepare("select \"offset\", value from orders_headers order by \"offset\"").unwrap();
+        let headers: Vec<(i64, Vec<u8>)> = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .map(|r| r.unwrap())
+            .collect();
+        assert_eq!(headers, vec![(3, b"w".to_vec()), (5, b"u".to_vec())]);
+    }
+}
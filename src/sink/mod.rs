@@ -0,0 +1,606 @@
+//! The databases and files a dump is written to.
+//!
+//! `--output` is either the file name of a SQLite or DuckDB database, the
+//! URL of a PostgreSQL or MySQL database or, with the other formats, a
+//! directory of Parquet, CSV or JSON Lines files. All of them get one table
+//! per topic with the same columns.
+
+#[cfg(any(feature = "duckdb", feature = "mysql", feature = "postgres"))]
+mod buffer;
+#[cfg(feature = "duckdb")]
+mod duckdb;
+#[cfg(feature = "mysql")]
+mod mysql;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+mod text;
+
+use std::collections::HashMap;
+use std::fs::{read_dir, remove_dir_all, remove_file, rename};
+use std::io::{self, ErrorKind};
+use std::path::{Path, is_separator};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use regex::Regex;
+use columns::Column;
+use error::{Error, Result};
+use value::SqlValue;
+use Args;
+
+/// A message as it is written into the table of its topic.
+pub struct Row<'a> {
+    pub partition: i32,
+    pub offset: i64,
+    pub key: SqlValue,
+    pub value: SqlValue,
+    /// Milliseconds since the epoch.
+    pub timestamp: Option<i64>,
+    pub timestamp_type: Option<&'static str>,
+    /// The values of the columns the table was created with, in the same
+    /// order.
+    pub columns: Vec<SqlValue>,
+    pub headers: &'a [(String, Option<Vec<u8>>)]
+}
+
+impl<'a> Row<'a> {
+    /// The values of all columns of the table, in the order of the columns.
+    pub fn values(&self) -> Vec<SqlValue> {
+        let mut values = vec![
+            SqlValue::Integer(self.partition as i64),
+            SqlValue::Integer(self.offset),
+            self.key.clone(),
+            self.value.clone(),
+            self.timestamp.map_or(SqlValue::Null, SqlValue::Integer),
+            self.timestamp_type.map_or(SqlValue::Null, |t| SqlValue::Text(t.to_string()))
+        ];
+        values.extend(self.columns.iter().cloned());
+        values
+    }
+}
+
+/// The tables of a dump, while it is written. A new snapshot only replaces
+/// the previous dump, when it is complete. SQLite, DuckDB and PostgreSQL
+/// write it in a single transaction. MySQL, which commits whenever a table
+/// is created, writes it into separate tables, and the files are written
+/// into a separate directory.
+///
+/// `begin` is called once for each topic before its messages are written,
+/// `finish` once after all messages were written. The outputs are opened by
+/// `with_sink`, which commits the data when `finish` succeeded.
+pub trait Sink {
+    /// Creates the table of `topic` and its headers table, unless they
+    /// exist. The table has `columns` in addition to the columns every table
+    /// has.
+    fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()>;
+
+    /// Returns the column names of an existing table or `None`, if the
+    /// table does not exist.
+    fn table_columns(&mut self, topic: &str) -> Result<Option<Vec<String>>>;
+
+    /// Writes messages of `topic` in the order they were read. In compact
+    /// mode each replaces the message with the same key.
+    fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()>;
+
+    /// Deletes the message with `key`. Only used in compact mode.
+    fn tombstone(&mut self, topic: &str, key: SqlValue) -> Result<()>;
+
+    /// Commits the data written so far and starts a new transaction. Only
+    /// used in follow mode.
+    fn checkpoint(&mut self) -> Result<()>;
+
+    /// Writes everything which is still buffered.
+    fn finish(&mut self) -> Result<()>;
+}
+
+/// How a dump is stored.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    /// A database, whose kind follows from `--output`.
+    Sql,
+    Parquet,
+    Csv,
+    /// JSON Lines.
+    Jsonl
+}
+
+impl Format {
+    pub fn parse(s: &str) -> ::std::result::Result<Format, String> {
+        match s {
+            "sql" => Ok(Format::Sql),
+            "parquet" => Ok(Format::Parquet),
+            "csv" => Ok(Format::Csv),
+            "jsonl" => Ok(Format::Jsonl),
+            _ => Err(format!("'{}' is not one of 'sql', 'parquet', 'csv' and 'jsonl'", s))
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Format::Sql => "sql",
+            Format::Parquet => "parquet",
+            Format::Csv => "csv",
+            Format::Jsonl => "jsonl"
+        }
+    }
+
+    /// The output, when none is given.
+    pub fn default_output(&self) -> &'static str {
+        match *self {
+            Format::Sql => "dump.sqlite",
+            Format::Parquet | Format::Csv | Format::Jsonl => "dump"
+        }
+    }
+
+    /// Whether messages can be replaced and dumps be resumed. Only databases
+    /// support it.
+    pub fn updatable(&self) -> bool {
+        *self == Format::Sql
+    }
+}
+
+/// How binary keys, values and headers are written into text files.
+#[derive(Clone, Copy)]
+pub enum BinaryEncoding {
+    Base64,
+    Hex,
+    /// Invalid UTF-8 is replaced.
+    Utf8
+}
+
+impl BinaryEncoding {
+    pub fn parse(s: &str) -> ::std::result::Result<BinaryEncoding, String> {
+        match s {
+            "base64" => Ok(BinaryEncoding::Base64),
+            "hex" => Ok(BinaryEncoding::Hex),
+            "utf8" => Ok(BinaryEncoding::Utf8),
+            _ => Err(format!("'{}' is not one of 'base64', 'hex' and 'utf8'", s))
+        }
+    }
+
+    fn encode(&self, bytes: &[u8]) -> String {
+        match *self {
+            BinaryEncoding::Base64 => STANDARD.encode(bytes),
+            BinaryEncoding::Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+            BinaryEncoding::Utf8 => String::from_utf8_lossy(bytes).into_owned()
+        }
+    }
+}
+
+/// The table of a topic. Topic names may contain '.' and '-', which are
+/// replaced like any other character that is not a letter, a digit or '_'.
+pub fn table_name(topic: &str) -> String {
+    topic.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+        .collect()
+}
+
+/// The table holding the headers of the messages in `table_name(topic)`.
+pub fn headers_table_name(topic: &str) -> String {
+    format!("{}_headers", table_name(topic))
+}
+
+/// Quotes a table or column name with `quote`, which is doubled inside the
+/// name, so the name may be a keyword like `order` or `offset`.
+fn quote_identifier(quote: char, name: &str) -> String {
+    let doubled: String = [quote, quote].iter().collect();
+    format!("{}{}{}", quote, name.replace(quote, &doubled), quote)
+}
+
+/// Quotes a table or column name like standard SQL.
+fn quote(name: &str) -> String {
+    quote_identifier('"', name)
+}
+
+/// The name of a file of the table `name` in an output directory. With
+/// `--max-file-size` the files of a table are numbered, starting at 0.
+fn file_name(args: &Args, name: &str, number: usize, extension: &str) -> String {
+    if args.max_file_size.is_some() {
+        format!("{}-{:05}.{}", name, number, extension)
+    } else {
+        format!("{}.{}", name, extension)
+    }
+}
+
+/// Returns the file the data is written to. A new snapshot is written to a
+/// temporary file which is renamed when the dump is complete, so the output
+/// file is never left half-written. Resumed and followed dumps are written
+/// to the output file directly.
+fn write_path(args: &Args) -> String {
+    if args.resume || args.follow {
+        args.output.clone()
+    } else {
+        // With a trailing separator the file would end up inside a directory
+        // given as output.
+        format!("{}.partial", args.output.trim_end_matches(is_separator))
+    }
+}
+
+/// Returns the name a directory output is moved to, while it is replaced by
+/// a new snapshot. Unlike a file, a directory cannot be replaced by a
+/// rename, so the previous dump is moved aside first and only deleted, when
+/// the new one is in place.
+fn old_path(args: &Args) -> String {
+    format!("{}.old", args.output.trim_end_matches(is_separator))
+}
+
+/// The suffixes of the files SQLite and DuckDB keep next to a database.
+const JOURNAL_SUFFIXES: [&str; 4] = ["-wal", "-shm", "-journal", ".wal"];
+
+fn ignore_not_found(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        result => result
+    }
+}
+
+/// Removes the journals of a database file. A journal left behind would be
+/// applied to a new database with the same name.
+fn remove_journals(path: &str) -> io::Result<()> {
+    for suffix in &JOURNAL_SUFFIXES {
+        ignore_not_found(remove_file(format!("{}{}", path, suffix)))?;
+    }
+    Ok(())
+}
+
+/// Whether a directory holds nothing but the files of a dump, so it may be
+/// replaced.
+fn is_dump_directory(path: &str) -> io::Result<bool> {
+    let re = Regex::new(r"^[A-Za-z0-9_]+(-[0-9]{5})?\.(parquet|csv|jsonl)$").unwrap();
+    for entry in read_dir(path)? {
+        let entry = entry?;
+        let dump_file = entry.file_type()?.is_file() && entry.file_name().to_str().is_some_and(|name| re.is_match(name));
+        if !dump_file {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Removes a file with its journals or a directory with its content, if it
+/// exists.
+fn remove(path: &str) -> io::Result<()> {
+    if Path::new(path).is_dir() {
+        ignore_not_found(remove_dir_all(path))
+    } else {
+        ignore_not_found(remove_file(path))?;
+        remove_journals(path)
+    }
+}
+
+/// Whether the database file or output directory of a previous dump exists.
+fn file_exists(args: &Args) -> bool {
+    Path::new(&args.output).exists()
+}
+
+/// Calls `write` with the path of the database file or output directory to
+/// write. A previous one is removed first, unless the dump is resumed. In
+/// follow mode that is the output itself, so an existing dump is deleted
+/// before the new one is started. A temporary file or a moved directory
+/// which already exists is only removed with `--overwrite`.
+fn with_file<F>(args: &Args, write: F) -> Result<()>
+    where F: FnOnce(&str) -> Result<()>
+{
+    let write_path = write_path(args);
+    let old_path = old_path(args);
+    if !args.resume {
+        // A directory given by mistake, like the current one, is never deleted.
+        for path in &[&args.output, &write_path, &old_path] {
+            if Path::new(path).is_dir() && !is_dump_directory(path).map_err(Error::Output)? {
+                return Err(Error::Args(format!(
+                    "'{}' contains other files than those of a dump, so it is not replaced.", path)));
+            }
+        }
+        // The temporary file may be left from an interrupted dump, but it
+        // may just as well be someone else's file. A moved directory is left
+        // when a dump was interrupted, while the previous one was replaced,
+        // and may be the only copy of it.
+        let left = (write_path != args.output && Path::new(&write_path).is_file()) || Path::new(&old_path).is_dir();
+        if left && !args.overwrite {
+            let path = if Path::new(&old_path).is_dir() { &old_path } else { &write_path };
+            return Err(Error::Args(format!(
+                "'{}' already exists. Use --overwrite to replace it.", path)));
+        }
+        remove(&write_path).map_err(Error::Output)?;
+        if Path::new(&old_path).is_dir() {
+            remove_dir_all(&old_path).map_err(Error::Output)?;
+        }
+    }
+    let result = write(&write_path);
+    if write_path != args.output {
+        match result {
+            Ok(()) => {
+                if Path::new(&args.output).is_dir() {
+                    rename(&args.output, &old_path).map_err(Error::Output)?;
+                    if let Err(e) = rename(&write_path, &args.output) {
+                        let _ = rename(&old_path, &args.output);
+                        return Err(Error::Output(e));
+                    }
+                    remove_dir_all(&old_path).map_err(Error::Output)?;
+                } else {
+                    remove_journals(&args.output).map_err(Error::Output)?;
+                    rename(&write_path, &args.output).map_err(Error::Output)?
+                }
+            }
+            Err(_) => { let _ = remove(&write_path); }
+        }
+    }
+    result
+}
+
+/// The kinds of outputs a dump can be written to. All but SQLite and the
+/// text files are cargo features.
+enum Kind {
+    Sqlite,
+    #[cfg(feature = "duckdb")]
+    Duckdb,
+    #[cfg(feature = "postgres")]
+    Postgres,
+    #[cfg(feature = "mysql")]
+    Mysql,
+    #[cfg(feature = "parquet")]
+    Parquet,
+    /// CSV or JSON Lines.
+    Text
+}
+
+fn kind(args: &Args) -> Result<Kind> {
+    let output = &args.output;
+    let (name, feature) = if args.format == Format::Parquet {
+        ("Parquet files", "parquet")
+    } else if args.format == Format::Csv || args.format == Format::Jsonl {
+        return Ok(Kind::Text);
+    } else if output.starts_with("postgres://") || output.starts_with("postgresql://") {
+        ("PostgreSQL databases", "postgres")
+    } else if output.starts_with("mysql://") {
+        ("MySQL databases", "mysql")
+    } else if output.ends_with(".duckdb") || output.ends_with(".ddb") {
+        ("DuckDB files", "duckdb")
+    } else {
+        return Ok(Kind::Sqlite);
+    };
+    match feature {
+        #[cfg(feature = "parquet")]
+        "parquet" => Ok(Kind::Parquet),
+        #[cfg(feature = "postgres")]
+        "postgres" => Ok(Kind::Postgres),
+        #[cfg(feature = "mysql")]
+        "mysql" => Ok(Kind::Mysql),
+        #[cfg(feature = "duckdb")]
+        "duckdb" => Ok(Kind::Duckdb),
+        _ => Err(Error::Args(format!(
+            "This build cannot write {}. Build it with '--features {}'.", name, feature)))
+    }
+}
+
+/// Whether a previous dump exists at `args.output`.
+pub fn exists(args: &Args) -> Result<bool> {
+    match kind(args)? {
+        Kind::Sqlite => Ok(file_exists(args)),
+        #[cfg(feature = "duckdb")]
+        Kind::Duckdb => Ok(file_exists(args)),
+        #[cfg(feature = "postgres")]
+        Kind::Postgres => postgres::exists(args),
+        #[cfg(feature = "mysql")]
+        Kind::Mysql => mysql::exists(args),
+        #[cfg(feature = "parquet")]
+        Kind::Parquet => Ok(file_exists(args)),
+        Kind::Text => Ok(file_exists(args))
+    }
+}
+
+/// Returns the offsets after the last message stored for each partition of
+/// `topic` in an existing dump. Only databases can be resumed.
+pub fn stored_offsets(args: &Args, topic: &str) -> Result<HashMap<i32, i64>> {
+    match kind(args)? {
+        Kind::Sqlite => sqlite::stored_offsets(args, topic),
+        #[cfg(feature = "duckdb")]
+        Kind::Duckdb => duckdb::stored_offsets(args, topic),
+        #[cfg(feature = "postgres")]
+        Kind::Postgres => postgres::stored_offsets(args, topic),
+        #[cfg(feature = "mysql")]
+        Kind::Mysql => mysql::stored_offsets(args, topic),
+        #[cfg(feature = "parquet")]
+        Kind::Parquet => Ok(HashMap::new()),
+        Kind::Text => Ok(HashMap::new())
+    }
+}
+
+/// Opens the output and passes it to `f`. Unless a dump is resumed, a
+/// previous dump is replaced. The data is committed when `f` succeeds.
+pub fn with_sink<F>(args: &Args, f: F) -> Result<()>
+    where F: FnOnce(&mut dyn Sink) -> Result<()>
+{
+    match kind(args)? {
+        Kind::Sqlite => with_file(args, |path| sqlite::write(args, path, f)),
+        #[cfg(feature = "duckdb")]
+        Kind::Duckdb => with_file(args, |path| duckdb::write(args, path, f)),
+        #[cfg(feature = "postgres")]
+        Kind::Postgres => postgres::with_sink(args, f),
+        #[cfg(feature = "mysql")]
+        Kind::Mysql => mysql::with_sink(args, f),
+        #[cfg(feature = "parquet")]
+        Kind::Parquet => with_file(args, |path| parquet::write(args, path, f)),
+        Kind::Text => with_file(args, |path| text::write(args, path, f))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, create_dir, read_dir, read_to_string, write};
+    use std::path::Path;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc::sync_channel;
+    use tempfile::TempDir;
+    use columns::Column;
+    use decode::{Decoder, Decoders};
+    use error::{Error, Result};
+    use progress::Progress;
+    use tests::{dump_to, query, record, run_dump};
+    use value::SqlValue;
+    use {parse_args, write_dump};
+    use super::{Row, Sink};
+
+    /// A call of a `Sink`.
+    #[derive(Debug, PartialEq)]
+    enum Call {
+        /// The topic and the names of its columns.
+        Begin(String, Vec<String>),
+        /// The topic and the values of its rows.
+        Write(String, Vec<Vec<SqlValue>>),
+        Tombstone(String, SqlValue),
+        Checkpoint,
+        Finish
+    }
+
+    /// A sink which only records how it is called.
+    #[derive(Default)]
+    struct RecordingSink {
+        calls: Vec<Call>
+    }
+
+    impl Sink for RecordingSink {
+        fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()> {
+            self.calls.push(Call::Begin(topic.to_string(), columns.iter().map(|c| c.name.clone()).collect()));
+            Ok(())
+        }
+
+        fn table_columns(&mut self, _topic: &str) -> Result<Option<Vec<String>>> {
+            Ok(None)
+        }
+
+        fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()> {
+            self.calls.push(Call::Write(topic.to_string(), rows.iter().map(|row| row.values()).collect()));
+            Ok(())
+        }
+
+        fn tombstone(&mut self, topic: &str, key: SqlValue) -> Result<()> {
+            self.calls.push(Call::Tombstone(topic.to_string(), key));
+            Ok(())
+        }
+
+        fn checkpoint(&mut self) -> Result<()> {
+            self.calls.push(Call::Checkpoint);
+            Ok(())
+        }
+
+        fn finish(&mut self) -> Result<()> {
+            self.calls.push(Call::Finish);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn sink_calls() {
+        let args = parse_args(vec!["dump-kafka-to-sql", "-t", "orders", "--no-progress", "--compact", "--infer-schema",
+                                   "--key-format", "utf8", "--value-format", "json"]);
+        let decoders = Decoders {
+            key: Decoder::new(args.key_format, None, None, None).unwrap(),
+            value: Decoder::new(args.value_format, None, None, None).unwrap()
+        };
+        let (tx, rx) = sync_channel(10);
+        tx.send(vec![record(0, 0, "a", r#"{"id":1}"#), record(0, 1, "b", r#"{"id":2}"#)]).unwrap();
+        tx.send(vec![record(0, 2, "b", "")]).unwrap();
+        drop(tx);
+        let mut sink = RecordingSink::default();
+        write_dump(&args, &mut sink, decoders, Arc::new(Mutex::new(Progress::new(false))), rx, vec![]).unwrap();
+
+        let row = |offset: i64, key: &str, value: &str, id: i64| vec![
+            SqlValue::Integer(0),
+            SqlValue::Integer(offset),
+            SqlValue::Text(key.to_string()),
+            SqlValue::Text(value.to_string()),
+            SqlValue::Integer(1_700_000_000_000 + offset * 1_000),
+            SqlValue::Text("CreateTime".to_string()),
+            SqlValue::Integer(id)
+        ];
+        // The columns are inferred, when all messages were read, so the
+        // tombstone is among the sampled messages.
+        assert_eq!(sink.calls, vec![
+            Call::Begin("orders".to_string(), vec!["id".to_string()]),
+            Call::Write("orders".to_string(), vec![row(0, "a", r#"{"id":1}"#, 1), row(1, "b", r#"{"id":2}"#, 2)]),
+            Call::Tombstone("orders".to_string(), SqlValue::Text("b".to_string())),
+            Call::Finish
+        ]);
+    }
+
+    #[test]
+    fn failed_overwrite() {
+        let dir = TempDir::new().unwrap();
+        run_dump(&dir, vec![record(0, 0, "k", "{}"), record(0, 1, "k", "{}")], &[]).unwrap();
+        match run_dump(&dir, vec![record(0, 0, "k", "not json")], &["--overwrite", "--value-format", "json"]) {
+            Err(Error::Decode(_)) => {}
+            result => panic!("{:?}", result)
+        }
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["2"]);
+        assert!(!dir.path().join("dump.sqlite.partial").exists());
+    }
+
+    #[test]
+    fn partial_file() {
+        let dir = TempDir::new().unwrap();
+        let partial = dir.path().join("dump.sqlite.partial");
+        write(&partial, "precious").unwrap();
+        match run_dump(&dir, vec![record(0, 0, "k", "v")], &[]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("dump.sqlite.partial' already exists"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        assert_eq!(read_to_string(&partial).unwrap(), "precious");
+        assert!(!dir.path().join("dump.sqlite").exists());
+
+        run_dump(&dir, vec![record(0, 0, "k", "v")], &["--overwrite"]).unwrap();
+        assert_eq!(query(&dir, "select count(*) || '' from orders"), vec!["1"]);
+        assert!(!partial.exists());
+    }
+
+    #[test]
+    fn overwrite_directory() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        let output = output.to_str().unwrap();
+        dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "csv", "--max-file-size", "1"]).unwrap();
+        dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "csv", "--overwrite"]).unwrap();
+        let mut names: Vec<String> = read_dir(output).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["orders.csv", "orders_headers.csv"]);
+        assert!(!dir.path().join("dump.old").exists());
+    }
+
+    #[test]
+    fn old_directory() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        let output = output.to_str().unwrap();
+        // A dump interrupted after the previous one was moved aside.
+        let old = dir.path().join("dump.old");
+        create_dir(&old).unwrap();
+        write(old.join("orders.csv"), "precious").unwrap();
+        match dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "csv"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("dump.old' already exists"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        assert_eq!(read_to_string(old.join("orders.csv")).unwrap(), "precious");
+
+        dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "csv", "--overwrite"]).unwrap();
+        assert!(!old.exists());
+        assert!(Path::new(output).join("orders.csv").exists());
+    }
+
+    #[test]
+    fn foreign_directory() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        create_dir(&output).unwrap();
+        File::create(output.join("notes.txt")).unwrap();
+        match dump_to(output.to_str().unwrap(), vec![record(0, 0, "k", "v")], &["--format", "csv", "--overwrite"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("contains other files than those of a dump"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        assert!(output.join("notes.txt").exists());
+        assert!(!dir.path().join("dump.partial").exists());
+    }
+}
@@ -0,0 +1,366 @@
+//! Writing a dump into Parquet files.
+//!
+//! `--output` is a directory with a `<table>.parquet` and a
+//! `<table>_headers.parquet` file per topic. With `--max-file-size` the
+//! files are rolled into `<table>-00000.parquet`, `<table>-00001.parquet`
+//! and so on. The messages are buffered and written as record batches,
+//! which are kept small enough for the files to stay close to that size.
+//!
+//! Parquet files cannot be changed once they are written, so compacted and
+//! resumed dumps are not supported. A file can only be read once it is
+//! closed, which happens when it is rolled or the dump is complete. So in
+//! follow mode `--max-file-size` is required and the buffered messages are
+//! written on every checkpoint, which lets the files reach their size.
+
+use std::fs::{File, create_dir_all};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::collections::HashMap;
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_array::builder::{BinaryBuilder, Float64Builder, Int32Builder, Int64Builder, StringBuilder};
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use columns::Column;
+use error::{Error, Result};
+use value::{SqlType, SqlValue};
+use Args;
+use super::{Row, Sink, file_name, headers_table_name, table_name};
+
+/// The number of buffered messages of a topic at which they are written as
+/// a record batch.
+const BATCH_ROWS: usize = 10_000;
+
+/// With `--max-file-size` the buffered messages are also written, when
+/// their size reaches this fraction of the maximum, so a file exceeds it by
+/// at most that much.
+const BATCH_FRACTION: usize = 16;
+
+/// The approximate number of bytes a value takes in a record batch.
+fn value_size(value: &SqlValue) -> usize {
+    match *value {
+        SqlValue::Text(ref s) => s.len(),
+        SqlValue::Blob(ref b) => b.len(),
+        _ => 8
+    }
+}
+
+fn data_type(sql_type: SqlType) -> DataType {
+    match sql_type {
+        SqlType::Integer => DataType::Int64,
+        SqlType::Real => DataType::Float64,
+        SqlType::Text | SqlType::Json => DataType::Utf8,
+        SqlType::Blob => DataType::Binary
+    }
+}
+
+pub fn write<F>(args: &Args, path: &str, f: F) -> Result<()>
+    where F: FnOnce(&mut dyn Sink) -> Result<()>
+{
+    create_dir_all(path).map_err(Error::Output)?;
+    let mut sink = ParquetSink {
+        args,
+        dir: PathBuf::from(path),
+        tables: HashMap::new()
+    };
+    f(&mut sink)
+}
+
+/// Collects the values of a column until they are written.
+enum ColumnBuilder {
+    Int32(Int32Builder),
+    Int64(Int64Builder),
+    Float64(Float64Builder),
+    Utf8(StringBuilder),
+    Binary(BinaryBuilder)
+}
+
+impl ColumnBuilder {
+    fn new(data_type: &DataType) -> ColumnBuilder {
+        match *data_type {
+            DataType::Int32 => ColumnBuilder::Int32(Int32Builder::new()),
+            DataType::Int64 => ColumnBuilder::Int64(Int64Builder::new()),
+            DataType::Float64 => ColumnBuilder::Float64(Float64Builder::new()),
+            DataType::Binary => ColumnBuilder::Binary(BinaryBuilder::new()),
+            _ => ColumnBuilder::Utf8(StringBuilder::new())
+        }
+    }
+
+    /// Appends a value. Values which do not match the type of the column
+    /// are stored as null.
+    fn append(&mut self, value: &SqlValue) {
+        match (self, value) {
+            (&mut ColumnBuilder::Int32(ref mut b), &SqlValue::Integer(i)) => b.append_value(i as i32),
+            (&mut ColumnBuilder::Int64(ref mut b), &SqlValue::Integer(i)) => b.append_value(i),
+            (&mut ColumnBuilder::Float64(ref mut b), &SqlValue::Real(r)) => b.append_value(r),
+            (&mut ColumnBuilder::Float64(ref mut b), &SqlValue::Integer(i)) => b.append_value(i as f64),
+            (&mut ColumnBuilder::Utf8(ref mut b), SqlValue::Text(s)) => b.append_value(s),
+            (&mut ColumnBuilder::Binary(ref mut b), SqlValue::Blob(v)) => b.append_value(v),
+            (&mut ColumnBuilder::Int32(ref mut b), _) => b.append_null(),
+            (&mut ColumnBuilder::Int64(ref mut b), _) => b.append_null(),
+            (&mut ColumnBuilder::Float64(ref mut b), _) => b.append_null(),
+            (&mut ColumnBuilder::Utf8(ref mut b), _) => b.append_null(),
+            (&mut ColumnBuilder::Binary(ref mut b), _) => b.append_null()
+        }
+    }
+
+    /// Returns the collected values and starts over.
+    fn finish(&mut self) -> ArrayRef {
+        match *self {
+            ColumnBuilder::Int32(ref mut b) => Arc::new(b.finish()),
+            ColumnBuilder::Int64(ref mut b) => Arc::new(b.finish()),
+            ColumnBuilder::Float64(ref mut b) => Arc::new(b.finish()),
+            ColumnBuilder::Utf8(ref mut b) => Arc::new(b.finish()),
+            ColumnBuilder::Binary(ref mut b) => Arc::new(b.finish())
+        }
+    }
+}
+
+/// A table written into one Parquet file or, with `--max-file-size`, a
+/// sequence of them.
+struct TableFiles<'a> {
+    args: &'a Args,
+    dir: PathBuf,
+    name: String,
+    schema: SchemaRef,
+    builders: Vec<ColumnBuilder>,
+    /// The number of buffered rows.
+    rows: usize,
+    /// The approximate size of the buffered rows.
+    bytes: usize,
+    writer: Option<ArrowWriter<File>>,
+    /// The number of files started so far.
+    files: usize
+}
+
+impl<'a> TableFiles<'a> {
+    fn new(args: &'a Args, dir: &Path, name: String, fields: Vec<Field>) -> TableFiles<'a> {
+        let builders = fields.iter().map(|f| ColumnBuilder::new(f.data_type())).collect();
+        TableFiles {
+            args,
+            dir: dir.to_path_buf(),
+            name,
+            schema: Arc::new(Schema::new(fields)),
+            builders,
+            rows: 0,
+            bytes: 0,
+            writer: None,
+            files: 0
+        }
+    }
+
+    fn append(&mut self, values: &[SqlValue]) -> Result<()> {
+        for (builder, value) in self.builders.iter_mut().zip(values) {
+            builder.append(value);
+            self.bytes += value_size(value);
+        }
+        self.rows += 1;
+        let batch_bytes = self.args.max_file_size.map_or(usize::MAX, |max| max / BATCH_FRACTION);
+        if self.rows >= BATCH_ROWS || self.bytes >= batch_bytes {
+            self.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn open(&mut self) -> Result<ArrowWriter<File>> {
+        let file_name = file_name(self.args, &self.name, self.files, "parquet");
+        self.files += 1;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        let file = File::create(self.dir.join(file_name)).map_err(Error::Output)?;
+        Ok(ArrowWriter::try_new(file, self.schema.clone(), Some(properties))?)
+    }
+
+    /// Writes the buffered rows into the current file and rolls it, when it
+    /// reached the maximum size.
+    fn write_batch(&mut self) -> Result<()> {
+        if self.rows == 0 {
+            return Ok(());
+        }
+        let columns = self.builders.iter_mut().map(|b| b.finish()).collect();
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.rows = 0;
+        self.bytes = 0;
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => self.open()?
+        };
+        writer.write(&batch)?;
+        let full = self.args.max_file_size.is_some_and(|max| writer.bytes_written() + writer.in_progress_size() >= max);
+        if full {
+            writer.close()?;
+        } else {
+            self.writer = Some(writer);
+        }
+        Ok(())
+    }
+
+    /// Writes the buffered rows and closes the current file. A table
+    /// without rows still gets a file, so its schema is known.
+    fn close(mut self) -> Result<()> {
+        self.write_batch()?;
+        let writer = match self.writer.take() {
+            Some(writer) => Some(writer),
+            None if self.files == 0 => Some(self.open()?),
+            None => None
+        };
+        if let Some(writer) = writer {
+            writer.close()?;
+        }
+        Ok(())
+    }
+}
+
+struct Table<'a> {
+    rows: TableFiles<'a>,
+    headers: TableFiles<'a>
+}
+
+struct ParquetSink<'a> {
+    args: &'a Args,
+    dir: PathBuf,
+    tables: HashMap<String, Table<'a>>
+}
+
+impl<'a> Sink for ParquetSink<'a> {
+    fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()> {
+        let mut fields = vec![
+            Field::new("partition", DataType::Int32, false),
+            Field::new("offset", DataType::Int64, false),
+            Field::new("key", data_type(self.args.key_format.sql_type()), true),
+            Field::new("value", data_type(self.args.value_format.sql_type()), true),
+            Field::new("timestamp", DataType::Int64, true),
+            Field::new("timestamp_type", DataType::Utf8, true)
+        ];
+        fields.extend(columns.iter().map(|c| Field::new(c.name.as_str(), data_type(c.column_type.sql_type()), true)));
+        let header_fields = vec![
+            Field::new("partition", DataType::Int32, false),
+            Field::new("offset", DataType::Int64, false),
+            Field::new("name", DataType::Utf8, true),
+            Field::new("value", DataType::Binary, true)
+        ];
+        self.tables.insert(topic.to_string(), Table {
+            rows: TableFiles::new(self.args, &self.dir, table_name(topic), fields),
+            headers: TableFiles::new(self.args, &self.dir, headers_table_name(topic), header_fields)
+        });
+        Ok(())
+    }
+
+    fn table_columns(&mut self, _topic: &str) -> Result<Option<Vec<String>>> {
+        // Dumps are never resumed.
+        Ok(None)
+    }
+
+    fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()> {
+        let table = self.tables.get_mut(topic).unwrap();
+        for row in rows {
+            let values = row.values();
+            table.rows.append(&values)?;
+            for (name, value) in row.headers {
+                table.headers.append(&[
+                    SqlValue::Integer(row.partition as i64),
+                    SqlValue::Integer(row.offset),
+                    SqlValue::Text(name.clone()),
+                    value.clone().map_or(SqlValue::Null, SqlValue::Blob)
+                ])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tombstone(&mut self, _topic: &str, _key: SqlValue) -> Result<()> {
+        unreachable!("Parquet dumps are never compacted")
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        // The rows are only readable once their file is rolled.
+        for table in self.tables.values_mut() {
+            table.rows.write_batch()?;
+            table.headers.write_batch()?;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for (_, table) in self.tables.drain() {
+            table.rows.close()?;
+            table.headers.close()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{File, metadata, read_dir};
+    use std::path::Path;
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use tempfile::TempDir;
+    use error::Error;
+    use source::Record;
+    use tests::{dump_to, record};
+
+    /// The files in `dir` with their numbers of rows.
+    fn files(dir: &Path) -> Vec<(String, i64)> {
+        let mut files: Vec<(String, i64)> = read_dir(dir).unwrap()
+            .map(|entry| {
+                let entry = entry.unwrap();
+                let reader = SerializedFileReader::new(File::open(entry.path()).unwrap()).unwrap();
+                (entry.file_name().into_string().unwrap(), reader.metadata().file_metadata().num_rows())
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn tables() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        let mut records = vec![record(0, 0, "k", "v"), record(0, 1, "k", "v")];
+        records[1].headers = vec![("trace".to_string(), Some(b"x".to_vec())), ("empty".to_string(), None)];
+        dump_to(output.to_str().unwrap(), records, &["--format", "parquet"]).unwrap();
+        assert_eq!(files(&output), vec![("orders.parquet".to_string(), 2), ("orders_headers.parquet".to_string(), 2)]);
+    }
+
+    #[test]
+    fn rolling() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        // Random values, which cannot be compressed.
+        let mut seed: u64 = 1;
+        let records: Vec<Record> = (0..300).map(|o| {
+            let value: String = (0..1_000).map(|_| {
+                seed = seed.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1);
+                (b'a' + (seed >> 60) as u8) as char
+            }).collect();
+            record(0, o, "k", &value)
+        }).collect();
+        dump_to(output.to_str().unwrap(), records, &["--format", "parquet", "--max-file-size", "50000"]).unwrap();
+        let files = files(&output);
+        assert!(files.len() > 5, "{:?}", files);
+        assert_eq!(files.iter().map(|&(_, rows)| rows).sum::<i64>(), 300);
+        for (name, _) in files {
+            let size = metadata(output.join(&name)).unwrap().len();
+            assert!(size < 60_000, "{} has {} bytes", name, size);
+        }
+    }
+
+    #[test]
+    fn follow() {
+        let dir = TempDir::new().unwrap();
+        let output = dir.path().join("dump");
+        let output = output.to_str().unwrap();
+        match dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "parquet", "--follow"]) {
+            Err(Error::Args(msg)) => assert!(msg.contains("needs --max-file-size"), "{}", msg),
+            result => panic!("{:?}", result)
+        }
+        dump_to(output, vec![record(0, 0, "k", "v")], &["--format", "parquet", "--follow", "--max-file-size", "1000000"]).unwrap();
+        assert_eq!(files(Path::new(output)), vec![
+            ("orders-00000.parquet".to_string(), 1),
+            ("orders_headers-00000.parquet".to_string(), 0)
+        ]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Writing a dump into a SQLite file.
+
+use std::collections::HashMap;
+use rusqlite::{Connection, Statement};
+use rusqlite::types::ToSql;
+use columns::Column;
+use error::Result;
+use value::{SqlType, SqlValue};
+use Args;
+use super::{Row, Sink, file_exists, headers_table_name, quote, table_name};
+
+fn type_name(sql_type: SqlType) -> &'static str {
+    match sql_type {
+        SqlType::Integer => "integer",
+        SqlType::Real => "real",
+        // A column declared as `json` would get numeric affinity.
+        SqlType::Text | SqlType::Json => "text",
+        SqlType::Blob => "blob"
+    }
+}
+
+pub fn stored_offsets(args: &Args, topic: &str) -> Result<HashMap<i32, i64>> {
+    if !file_exists(args) {
+        return Ok(HashMap::new());
+    }
+    let conn = Connection::open(&args.output)?;
+    let table_name = table_name(topic);
+    let exists: i64 = conn.query_row(
+        "select count(*) from sqlite_master where type = 'table' and name = ?",
+        &[&table_name],
+        |row| row.get(0))?;
+    if exists == 0 {
+        return Ok(HashMap::new());
+    }
+    let mut stmt = conn.prepare(&format!("select partition, max(offset) from {} group by partition", quote(&table_name)))?;
+    let mut offsets = HashMap::new();
+    for row in stmt.query_map(&[], |row| (row.get(0), row.get::<i64>(1) + 1))? {
+        let (partition, offset) = row?;
+        offsets.insert(partition, offset);
+    }
+    Ok(offsets)
+}
+
+pub fn write<F>(args: &Args, path: &str, f: F) -> Result<()>
+    where F: FnOnce(&mut dyn Sink) -> Result<()>
+{
+    let conn = Connection::open(path)?;
+    if args.follow {
+        // Allow other processes to read while we write.
+        conn.execute_batch("pragma journal_mode = wal")?;
+    }
+    conn.execute_batch("begin")?;
+    {
+        let mut sink = SqliteSink {
+            args,
+            conn: &conn,
+            tables: HashMap::new()
+        };
+        f(&mut sink)?;
+    }
+    conn.execute_batch("commit")?;
+    Ok(())
+}
+
+/// A table and the statements to write into it and its headers table.
+struct Table<'conn> {
+    insert: Statement<'conn>,
+    delete: Statement<'conn>,
+    insert_header: Statement<'conn>,
+    /// Deletes the headers of the message with a key.
+    delete_headers: Statement<'conn>
+}
+
+struct SqliteSink<'a, 'conn> {
+    args: &'a Args,
+    conn: &'conn Connection,
+    tables: HashMap<String, Table<'conn>>
+}
+
+impl<'a, 'conn> Sink for SqliteSink<'a, 'conn> {
+    fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()> {
+        let table_name = quote(&table_name(topic));
+        let constraint = if self.args.compact {
+            ", unique (key) on conflict replace"
+        } else {
+            ""
+        };
+        let definitions: String = columns.iter()
+            .map(|c| format!(", {} {}", quote(&c.name), type_name(c.column_type.sql_type())))
+            .collect();
+        self.conn.execute(
+            &format!(
+                "create table if not exists {} (partition integer, offset integer, key {}, value {}, timestamp integer, timestamp_type text{}, primary key (partition, offset){})",
+                table_name,
+                type_name(self.args.key_format.sql_type()),
+                type_name(self.args.value_format.sql_type()),
+                definitions,
+                constraint),
+            &[])?;
+        let index_name = quote(&format!("{}_message", headers_table_name(topic)));
+        let headers_table_name = quote(&headers_table_name(topic));
+        self.conn.execute_batch(&format!(
+            "create table if not exists {0} (partition integer, offset integer, name text, value blob);
+             create index if not exists {1} on {0} (partition, offset);",
+            headers_table_name,
+            index_name))?;
+
+        let names: String = columns.iter().map(|c| format!(", {}", quote(&c.name))).collect();
+        let placeholders: String = columns.iter().map(|_| ", ?").collect();
+        let insert = self.conn.prepare(&format!(
+            "insert into {}(partition, offset, key, value, timestamp, timestamp_type{}) values(?, ?, ?, ?, ?, ?{})",
+            table_name,
+            names,
+            placeholders))?;
+        let delete = self.conn.prepare(&format!("delete from {} where key = ?", table_name))?;
+        let insert_header = self.conn.prepare(&format!(
+            "insert into {}(partition, offset, name, value) values(?, ?, ?, ?)",
+            headers_table_name))?;
+        let delete_headers = self.conn.prepare(&format!(
+            "delete from {0} where exists (select 1 from {1} where {1}.key = ? and {1}.partition = {0}.partition and {1}.offset = {0}.offset)",
+            headers_table_name,
+            table_name))?;
+        self.tables.insert(topic.to_string(), Table {
+            insert,
+            delete,
+            insert_header,
+            delete_headers
+        });
+        Ok(())
+    }
+
+    fn table_columns(&mut self, topic: &str) -> Result<Option<Vec<String>>> {
+        let mut stmt = self.conn.prepare(&format!("pragma table_info({})", quote(&table_name(topic))))?;
+        let mut names = vec![];
+        for name in stmt.query_map(&[], |row| row.get::<String>(1))? {
+            names.push(name?);
+        }
+        Ok(if names.is_empty() { None } else { Some(names) })
+    }
+
+    fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()> {
+        let table = self.tables.get_mut(topic).unwrap();
+        for row in rows {
+            if self.args.compact {
+                // The message with the same key is replaced.
+                table.delete_headers.execute(&[row.key.as_sql()])?;
+            }
+            let timestamp = row.timestamp.map_or(SqlValue::Null, SqlValue::Integer);
+            let timestamp_type = row.timestamp_type.map_or(SqlValue::Null, |t| SqlValue::Text(t.to_string()));
+            let mut params: Vec<&dyn ToSql> = vec![&row.partition, &row.offset, row.key.as_sql(), row.value.as_sql(),
+                                                   timestamp.as_sql(), timestamp_type.as_sql()];
+            params.extend(row.columns.iter().map(|v| v.as_sql()));
+            table.insert.execute(&params)?;
+            for (name, value) in row.headers {
+                table.insert_header.execute(&[&row.partition, &row.offset, name, value])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn tombstone(&mut self, topic: &str, key: SqlValue) -> Result<()> {
+        let table = self.tables.get_mut(topic).unwrap();
+        table.delete_headers.execute(&[key.as_sql()])?;
+        table.delete.execute(&[key.as_sql()])?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        self.conn.execute_batch("commit; begin")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        // Nothing is buffered.
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Writing a dump into CSV or JSON Lines files.
+//!
+//! Like with Parquet, `--output` is a directory with a file per topic,
+//! which may be rolled with `--max-file-size`. Each CSV file starts with a
+//! line of column names and the headers of the messages are written into
+//! `<table>_headers.csv`. In JSON Lines each message is an object, which
+//! contains its headers. Binary values are encoded as chosen with
+//! `--binary-encoding`.
+//!
+//! The files are flushed on every checkpoint, so in follow mode they can be
+//! read while they are written. Compacted and resumed dumps are not
+//! supported.
+
+use std::fs::{File, create_dir_all};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use serde_json;
+use serde_json::{Number, Value};
+use columns::Column;
+use error::{Error, Result};
+use value::{SqlType, SqlValue};
+use {Args, sink};
+use super::{BinaryEncoding, Row, Sink, file_name, headers_table_name, table_name};
+
+const HEADER_COLUMNS: [&str; 4] = ["partition", "offset", "name", "value"];
+
+/// Formats a value as a CSV field. Fields are only quoted when necessary.
+fn csv_field(encoding: BinaryEncoding, value: &SqlValue) -> String {
+    let text = match *value {
+        SqlValue::Null => return String::new(),
+        SqlValue::Integer(i) => return i.to_string(),
+        SqlValue::Real(r) => return r.to_string(),
+        SqlValue::Text(ref s) => s.clone(),
+        SqlValue::Blob(ref b) => encoding.encode(b)
+    };
+    if text.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", text.replace("\"", "\"\""))
+    } else {
+        text
+    }
+}
+
+fn csv_line<'v, I: Iterator<Item = &'v SqlValue>>(encoding: BinaryEncoding, values: I) -> String {
+    let fields: Vec<String> = values.map(|v| csv_field(encoding, v)).collect();
+    fields.join(",")
+}
+
+/// Converts a value to JSON. Text of a JSON column is embedded as it is.
+fn json_value(encoding: BinaryEncoding, sql_type: SqlType, value: &SqlValue) -> Value {
+    match *value {
+        SqlValue::Null => Value::Null,
+        SqlValue::Integer(i) => Value::from(i),
+        // NaN and infinity have no JSON representation.
+        SqlValue::Real(r) => Number::from_f64(r).map_or(Value::Null, Value::Number),
+        SqlValue::Text(ref s) if sql_type == SqlType::Json => serde_json::from_str(s).unwrap_or_else(|_| Value::from(s.as_str())),
+        SqlValue::Text(ref s) => Value::from(s.as_str()),
+        SqlValue::Blob(ref b) => Value::from(encoding.encode(b))
+    }
+}
+
+pub fn write<F>(args: &Args, path: &str, f: F) -> Result<()>
+    where F: FnOnce(&mut dyn Sink) -> Result<()>
+{
+    create_dir_all(path).map_err(Error::Output)?;
+    let mut sink = TextSink {
+        args,
+        dir: PathBuf::from(path),
+        tables: HashMap::new()
+    };
+    f(&mut sink)
+}
+
+/// A table written into one file or, with `--max-file-size`, a sequence of
+/// them.
+struct TableFiles<'a> {
+    args: &'a Args,
+    dir: PathBuf,
+    name: String,
+    extension: &'static str,
+    /// The line every file starts with.
+    first_line: Option<String>,
+    writer: Option<BufWriter<File>>,
+    /// The number of bytes in the current file.
+    size: usize,
+    /// The number of files started so far.
+    files: usize
+}
+
+impl<'a> TableFiles<'a> {
+    fn new(args: &'a Args, dir: &Path, name: String, first_line: Option<String>) -> TableFiles<'a> {
+        TableFiles {
+            args,
+            dir: dir.to_path_buf(),
+            name,
+            extension: if args.format == sink::Format::Csv { "csv" } else { "jsonl" },
+            first_line,
+            writer: None,
+            size: 0,
+            files: 0
+        }
+    }
+
+    fn open(&mut self) -> Result<BufWriter<File>> {
+        let file_name = file_name(self.args, &self.name, self.files, self.extension);
+        self.files += 1;
+        let mut writer = BufWriter::new(File::create(self.dir.join(file_name)).map_err(Error::Output)?);
+        self.size = 0;
+        if let Some(ref line) = self.first_line {
+            writeln!(writer, "{}", line).map_err(Error::Output)?;
+            self.size += line.len() + 1;
+        }
+        Ok(writer)
+    }
+
+    /// Writes a line into the current file and rolls it, when it reached the
+    /// maximum size.
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        let mut writer = match self.writer.take() {
+            Some(writer) => writer,
+            None => self.open()?
+        };
+        writeln!(writer, "{}", line).map_err(Error::Output)?;
+        self.size += line.len() + 1;
+        if self.args.max_file_size.is_some_and(|max| self.size >= max) {
+            writer.flush().map_err(Error::Output)?;
+        } else {
+            self.writer = Some(writer);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if let Some(ref mut writer) = self.writer {
+            writer.flush().map_err(Error::Output)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the current file. A table without rows still gets a file.
+    fn close(mut self) -> Result<()> {
+        if self.files == 0 {
+            self.writer = Some(self.open()?);
+        }
+        self.flush()
+    }
+}
+
+struct Table<'a> {
+    /// The types of the columns, in the order they are written.
+    types: Vec<SqlType>,
+    /// The names of the columns, encoded as JSON strings.
+    json_names: Vec<String>,
+    rows: TableFiles<'a>,
+    /// Only CSV has a separate headers file.
+    headers: Option<TableFiles<'a>>
+}
+
+struct TextSink<'a> {
+    args: &'a Args,
+    dir: PathBuf,
+    tables: HashMap<String, Table<'a>>
+}
+
+impl<'a> Sink for TextSink<'a> {
+    fn begin(&mut self, topic: &str, columns: &[Column]) -> Result<()> {
+        let mut names = vec!["partition", "offset", "key", "value", "timestamp", "timestamp_type"];
+        names.extend(columns.iter().map(|c| c.name.as_str()));
+        let mut types = vec![
+            SqlType::Integer,
+            SqlType::Integer,
+            self.args.key_format.sql_type(),
+            self.args.value_format.sql_type(),
+            SqlType::Integer,
+            SqlType::Text
+        ];
+        types.extend(columns.iter().map(|c| c.column_type.sql_type()));
+        let csv = self.args.format == sink::Format::Csv;
+        let encoding = self.args.binary_encoding.unwrap_or(BinaryEncoding::Base64);
+        let names_line = |names: &[&str]| {
+            let names: Vec<SqlValue> = names.iter().map(|n| SqlValue::Text(n.to_string())).collect();
+            csv_line(encoding, names.iter())
+        };
+        let table = Table {
+            types,
+            json_names: names.iter().map(|n| Value::from(*n).to_string()).collect(),
+            rows: TableFiles::new(self.args, &self.dir, table_name(topic), if csv { Some(names_line(&names)) } else { None }),
+            headers: if csv {
+                Some(TableFiles::new(self.args, &self.dir, headers_table_name(topic), Some(names_line(&HEADER_COLUMNS))))
+            } else {
+                None
+            }
+        };
+        self.tables.insert(topic.to_string(), table);
+        Ok(())
+    }
+
+    fn table_columns(&mut self, _topic: &str) -> Result<Option<Vec<String>>> {
+        // Dumps are never resumed.
+        Ok(None)
+    }
+
+    fn write_batch(&mut self, topic: &str, rows: Vec<Row>) -> Result<()> {
+        let encoding = self.args.binary_encoding.unwrap_or(BinaryEncoding::Base64);
+        let table = self.tables.get_mut(topic).unwrap();
+        for row in rows {
+            let values = row.values();
+            let headers: Vec<(SqlValue, SqlValue)> = row.headers.iter()
+                .map(|(name, value)| (SqlValue::Text(name.clone()), value.clone().map_or(SqlValue::Null, SqlValue::Blob)))
+                .collect();
+            match table.headers {
+                Some(ref mut headers_file) => {
+                    table.rows.write_line(&csv_line(encoding, values.iter()))?;
+                    for (name, value) in headers {
+                        let header = [SqlValue::Integer(row.partition as i64), SqlValue::Integer(row.offset), name, value];
+                        headers_file.write_line(&csv_line(encoding, header.iter()))?;
+                    }
+                }
+                None => {
+                    let mut fields: Vec<String> = table.json_names.iter()
+                        .zip(table.types.iter().zip(values.iter()))
+                        .map(|(name, (sql_type, value))| format!("{}:{}", name, json_value(encoding, *sql_type, value)))
+                        .collect();
+                    let headers: Vec<Value> = headers.iter()
+                        .map(|(name, value)| json!({
+                            "name": json_value(encoding, SqlType::Text, name),
+                            "value": json_value(encoding, SqlType::Blob, value)
+                        }))
+                        .collect();
+                    fields.push(format!("\"headers\":{}", Value::Array(headers)));
+                    table.rows.write_line(&format!("{{{}}}", fields.join(",")))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn tombstone(&mut self, _topic: &str, _key: SqlValue) -> Result<()> {
+        unreachable!("CSV and JSON Lines dumps are never compacted")
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        for (_, table) in self.tables.drain() {
+            table.rows.close()?;
+            if let Some(headers) = table.headers {
+                headers.close()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn checkpoint(&mut self) -> Result<()> {
+        for table in self.tables.values_mut() {
+            table.rows.flush()?;
+            if let Some(ref mut headers) = table.headers {
+                headers.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{read_dir, read_to_string, write};
+    use std::path::{Path, PathBuf};
+    use serde_json::{self, Value};
+    use tempfile::TempDir;
+    use source::Record;
+    use tests::{dump_to, record};
+    use value::SqlValue;
+    use super::{BinaryEncoding, csv_field};
+
+    /// Dumps `records` into the directory `dump` in `dir`.
+    fn dump(dir: &TempDir, records: Vec<Record>, options: &[&str]) -> PathBuf {
+        let output = dir.path().join("dump");
+        dump_to(output.to_str().unwrap(), records, options).unwrap();
+        output
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// The objects in a JSON Lines file.
+    fn json_lines(path: &Path) -> Vec<Value> {
+        read_to_string(path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+
+    #[test]
+    fn output_error() {
+        let dir = TempDir::new().unwrap();
+        write(dir.path().join("file"), "").unwrap();
+        let output = dir.path().join("file").join("dump");
+        let error = dump_to(output.to_str().unwrap(), vec![record(0, 0, "k", "v")], &["--format", "csv"]).unwrap_err();
+        assert_eq!(error.exit_code(), 3);
+    }
+
+    #[test]
+    fn csv_quoting() {
+        let field = |s: &str| csv_field(BinaryEncoding::Base64, &SqlValue::Text(s.to_string()));
+        assert_eq!(field("plain text"), "plain text");
+        assert_eq!(field("a,b"), "\"a,b\"");
+        assert_eq!(field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(field("two\nlines"), "\"two\nlines\"");
+        assert_eq!(field("cr\r"), "\"cr\r\"");
+        assert_eq!(csv_field(BinaryEncoding::Base64, &SqlValue::Null), "");
+        assert_eq!(csv_field(BinaryEncoding::Base64, &SqlValue::Integer(-3)), "-3");
+    }
+
+    #[test]
+    fn csv() {
+        let dir = TempDir::new().unwrap();
+        let mut r = record(0, 0, "k", "a,\"b\"");
+        r.headers.push(("trace".to_string(), Some(b"x".to_vec())));
+        let output = dump(&dir, vec![r, record(0, 1, "k", "v")], &["--format", "csv", "--key-format", "utf8", "--value-format", "utf8"]);
+        assert_eq!(file_names(&output), vec!["orders.csv", "orders_headers.csv"]);
+        assert_eq!(read_to_string(output.join("orders.csv")).unwrap(),
+                   "partition,offset,key,value,timestamp,timestamp_type\n\
+                    0,0,k,\"a,\"\"b\"\"\",1700000000000,CreateTime\n\
+                    0,1,k,v,1700000001000,CreateTime\n");
+        assert_eq!(read_to_string(output.join("orders_headers.csv")).unwrap(),
+                   "partition,offset,name,value\n0,0,trace,eA==\n");
+    }
+
+    #[test]
+    fn jsonl() {
+        let dir = TempDir::new().unwrap();
+        let mut r = record(0, 0, "k", "{\"status\": \"new\"}");
+        r.headers = vec![("trace".to_string(), Some(b"x".to_vec())), ("empty".to_string(), None)];
+        let output = dump(&dir, vec![r], &["--format", "jsonl", "--value-format", "json"]);
+        assert_eq!(file_names(&output), vec!["orders.jsonl"]);
+        assert_eq!(json_lines(&output.join("orders.jsonl")), vec![json!({
+            "partition": 0,
+            "offset": 0,
+            "key": "aw==",
+            "value": {"status": "new"},
+            "timestamp": 1_700_000_000_000i64,
+            "timestamp_type": "CreateTime",
+            "headers": [{"name": "trace", "value": "eA=="}, {"name": "empty", "value": null}]
+        })]);
+    }
+
+    #[test]
+    fn binary_encoding() {
+        let mut r = record(0, 0, "", "caf\u{e9}");
+        r.key = vec![0xff];
+        r.headers.push(("trace".to_string(), Some(vec![0x01, 0x02])));
+        for &(encoding, key, value, header) in &[("base64", "/w==", "Y2Fmw6k=", "AQI="),
+                                                 ("hex", "ff", "636166c3a9", "0102"),
+                                                 ("utf8", "\u{fffd}", "caf\u{e9}", "\u{1}\u{2}")] {
+            let dir = TempDir::new().unwrap();
+            let output = dump(&dir, vec![r.clone()], &["--format", "jsonl", "--binary-encoding", encoding]);
+            let line = &json_lines(&output.join("orders.jsonl"))[0];
+            assert_eq!(line["key"], json!(key), "{}", encoding);
+            assert_eq!(line["value"], json!(value), "{}", encoding);
+            assert_eq!(line["headers"][0]["value"], json!(header), "{}", encoding);
+        }
+    }
+
+    #[test]
+    fn rolling() {
+        let dir = TempDir::new().unwrap();
+        let records = (0..5).map(|o| record(0, o, "k", "v")).collect();
+        // The line of column names and two messages reach the size.
+        let output = dump(&dir, records, &["--format", "csv", "--max-file-size", "100"]);
+        assert_eq!(file_names(&output), vec!["orders-00000.csv", "orders-00001.csv", "orders-00002.csv", "orders_headers-00000.csv"]);
+        let offsets: Vec<Vec<String>> = ["orders-00000.csv", "orders-00001.csv", "orders-00002.csv"].iter()
+            .map(|name| {
+                let content = read_to_string(output.join(name)).unwrap();
+                let mut lines = content.lines();
+                assert_eq!(lines.next(), Some("partition,offset,key,value,timestamp,timestamp_type"));
+                lines.map(|line| line.split(',').nth(1).unwrap().to_string()).collect()
+            })
+            .collect();
+        assert_eq!(offsets, vec![vec!["0", "1"], vec!["2", "3"], vec!["4"]]);
+    }
+}
@@ -0,0 +1,200 @@
+//! Reading the messages from a Kafka cluster.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::mpsc::SyncSender;
+use std::time::Duration;
+use rdkafka::{Offset, TopicPartitionList};
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::message::{Headers, Message, Timestamp};
+use error::Result;
+use progress::Progress;
+use Args;
+use super::{BATCH_SIZE, Record, Source, TimestampType, TopicOffsets, plan_partitions};
+
+/// How long to wait for metadata and offsets from the cluster.
+const METADATA_TIMEOUT_MS: u64 = 10_000;
+
+/// How long to wait for a message, before the messages read so far are
+/// passed to the writer.
+const POLL_TIMEOUT_MS: u64 = 100;
+
+pub struct KafkaSource {
+    /// The brokers, separated by commas.
+    brokers: String
+}
+
+impl KafkaSource {
+    pub fn new(args: &Args) -> KafkaSource {
+        KafkaSource {
+            brokers: args.brokers.join(",")
+        }
+    }
+
+    /// Creates a consumer without subscriptions. Partitions are assigned
+    /// explicitly and no offsets are committed, but librdkafka still needs
+    /// a group id. When retention deletes messages before they are read, the
+    /// start offset is out of range. The consumer then fails with a Kafka
+    /// error instead of silently skipping to another offset.
+    fn create_consumer(&self) -> Result<BaseConsumer> {
+        Ok(ClientConfig::new()
+            .set("bootstrap.servers", &self.brokers)
+            .set("group.id", "dump-kafka-to-sql")
+            .set("enable.auto.commit", "false")
+            .set("enable.partition.eof", "true")
+            .set("auto.offset.reset", "error")
+            .create()?)
+    }
+}
+
+/// Looks up the offset of the first message produced at or after `time` for
+/// each partition. Partitions without such a message get their `latest`
+/// offset. librdkafka uses version 1 or later of the ListOffsets request,
+/// which finds the exact message. Version 0 only knows the first offset of
+/// each log segment, so it may return offsets long before `time`.
+fn offsets_for_time(consumer: &BaseConsumer, topic: &str, time: i64, latest: &HashMap<i32, i64>) -> Result<HashMap<i32, i64>> {
+    let mut query = TopicPartitionList::new();
+    for &partition in latest.keys() {
+        query.add_partition_offset(topic, partition, Offset::Offset(time))?;
+    }
+    let offsets = consumer.offsets_for_times(query, Duration::from_millis(METADATA_TIMEOUT_MS))?;
+    Ok(offsets.elements()
+        .iter()
+        .map(|e| (e.partition(), match e.offset() {
+            Offset::Offset(offset) => offset,
+            _ => latest[&e.partition()]
+        }))
+        .collect())
+}
+
+/// Stops fetching a partition which has been read completely.
+fn pause(consumer: &BaseConsumer, topic: &str, partition: i32) -> Result<()> {
+    let mut partitions = TopicPartitionList::new();
+    partitions.add_partition(topic, partition);
+    consumer.pause(&partitions)?;
+    Ok(())
+}
+
+impl Source for KafkaSource {
+    fn topic_names(&self) -> Result<Vec<String>> {
+        let consumer = self.create_consumer()?;
+        let metadata = consumer.fetch_metadata(None, Duration::from_millis(METADATA_TIMEOUT_MS))?;
+        Ok(metadata.topics().iter().map(|t| t.name().to_string()).collect())
+    }
+
+    fn read_topic(&self,
+                  args: &Args,
+                  topic: &str,
+                  resume: &HashMap<i32, i64>,
+                  progress: &Mutex<Progress>,
+                  tx: &SyncSender<Vec<Record>>) -> Result<()> {
+        let consumer = self.create_consumer()?;
+        let timeout = Duration::from_millis(METADATA_TIMEOUT_MS);
+        let metadata = consumer.fetch_metadata(Some(topic), timeout)?;
+        let mut earliest = HashMap::new();
+        let mut latest = HashMap::new();
+        for t in metadata.topics() {
+            if let Some(e) = t.error() {
+                return Err(KafkaError::MetadataFetch(e.into()).into());
+            }
+            for p in t.partitions() {
+                let (low, high) = consumer.fetch_watermarks(topic, p.id(), timeout)?;
+                earliest.insert(p.id(), low);
+                latest.insert(p.id(), high);
+            }
+        }
+        let from_time = match args.from_time {
+            Some(time) => Some(offsets_for_time(&consumer, topic, time, &latest)?),
+            None => None
+        };
+        let until_time = match args.until_time {
+            Some(time) => Some(offsets_for_time(&consumer, topic, time, &latest)?),
+            None => None
+        };
+        let offsets = TopicOffsets {
+            earliest,
+            latest,
+            from_time,
+            until_time
+        };
+        let mut partitions = plan_partitions(args, topic, resume, &offsets, progress)?;
+        let mut assignment = TopicPartitionList::new();
+        for (&partition, state) in &partitions {
+            if !state.done() {
+                assignment.add_partition_offset(topic, partition, Offset::Offset(state.position))?;
+            }
+        }
+        let mut remaining = partitions.values().filter(|state| !state.done()).count();
+        if remaining == 0 {
+            return Ok(());
+        }
+        consumer.assign(&assignment)?;
+        let poll_timeout = Duration::from_millis(POLL_TIMEOUT_MS);
+        while remaining > 0 {
+            let mut records = vec![];
+            while remaining > 0 && records.len() < BATCH_SIZE {
+                let m = match consumer.poll(poll_timeout) {
+                    None => break,
+                    Some(Ok(m)) => m,
+                    Some(Err(KafkaError::PartitionEOF(partition))) => {
+                        // Outside of follow mode the end is at most the latest
+                        // offset, so the partition is complete. Its last offsets
+                        // may be transaction markers, which are never delivered.
+                        let state = partitions.get_mut(&partition).unwrap();
+                        if !args.follow && !state.done() {
+                            state.position = state.end.unwrap();
+                            progress.lock().unwrap().finish_partition(topic, partition);
+                            remaining -= 1;
+                            pause(&consumer, topic, partition)?;
+                        }
+                        continue;
+                    }
+                    Some(Err(e)) => return Err(e.into())
+                };
+                let state = partitions.get_mut(&m.partition()).unwrap();
+                if state.done() || m.offset() < state.position {
+                    continue;
+                }
+                if state.end.is_none_or(|end| m.offset() < end) {
+                    let (timestamp, timestamp_type) = match m.timestamp() {
+                        Timestamp::NotAvailable => (None, None),
+                        Timestamp::CreateTime(t) => (Some(t), Some(TimestampType::CreateTime)),
+                        Timestamp::LogAppendTime(t) => (Some(t), Some(TimestampType::LogAppendTime))
+                    };
+                    let headers = match m.headers() {
+                        Some(headers) => headers.iter()
+                            .map(|h| (h.key.to_string(), h.value.map(|v| v.to_vec())))
+                            .collect(),
+                        None => vec![]
+                    };
+                    records.push(Record {
+                        topic: topic.to_string(),
+                        partition: m.partition(),
+                        offset: m.offset(),
+                        key: m.key().unwrap_or(&[]).to_vec(),
+                        value: m.payload().unwrap_or(&[]).to_vec(),
+                        timestamp,
+                        timestamp_type,
+                        headers
+                    });
+                    state.position = m.offset() + 1;
+                } else {
+                    // Compacted topics have gaps before the end.
+                    state.position = state.end.unwrap();
+                    progress.lock().unwrap().finish_partition(topic, m.partition());
+                }
+                if state.done() {
+                    remaining -= 1;
+                    pause(&consumer, topic, m.partition())?;
+                }
+            }
+            // When sending fails, the writer stopped and reports its own error.
+            if !records.is_empty() && tx.send(records).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
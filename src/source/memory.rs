@@ -0,0 +1,261 @@
+//! Reading messages which are held in memory, like the messages of a
+//! fixture file.
+//!
+//! A fixture file has a JSON object per line, like the ones printed by
+//! `--echo json`:
+//!
+//! ```text
+//! {"topic": "orders", "partition": 0, "offset": 7, "timestamp": 1700000000000, "key": "17", "value": "{\"status\": \"new\"}", "headers": [{"name": "trace", "value": "a1"}]}
+//! ```
+//!
+//! Only `topic`, `partition` and `offset` are required. Keys, values and
+//! header values are UTF-8 text. Binary ones, like Avro or protobuf
+//! messages, are given as `key_base64` or `key_hex` instead of `key` and
+//! likewise for values. `timestamp_type` is `CreateTime` or
+//! `LogAppendTime` and defaults to `CreateTime`.
+
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::sync::Mutex;
+use std::sync::mpsc::SyncSender;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde_json::{self, Value};
+use error::{Error, Result};
+use progress::Progress;
+use Args;
+use super::{BATCH_SIZE, Record, Source, TimestampType, TopicOffsets, plan_partitions};
+
+pub struct MemorySource {
+    records: Vec<Record>
+}
+
+impl MemorySource {
+    pub fn new(records: Vec<Record>) -> MemorySource {
+        MemorySource {
+            records
+        }
+    }
+
+    /// Reads the messages of a fixture file.
+    pub fn from_file(path: &str) -> Result<MemorySource> {
+        let mut records = vec![];
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let invalid = |reason: String| Error::Args(format!("Invalid message in line {} of '{}': {}", i + 1, path, reason));
+            let json: Value = serde_json::from_str(&line).map_err(|e| invalid(e.to_string()))?;
+            records.push(parse_record(&json).map_err(invalid)?);
+        }
+        Ok(MemorySource::new(records))
+    }
+
+    /// The offsets of `topic`, as Kafka would report them.
+    fn offsets(&self, args: &Args, topic: &str) -> TopicOffsets {
+        let mut earliest = HashMap::new();
+        let mut latest = HashMap::new();
+        for r in self.records.iter().filter(|r| r.topic == topic) {
+            let first = earliest.entry(r.partition).or_insert(r.offset);
+            *first = min(*first, r.offset);
+            let end = latest.entry(r.partition).or_insert(r.offset + 1);
+            *end = max(*end, r.offset + 1);
+        }
+        TopicOffsets {
+            from_time: args.from_time.map(|time| self.offsets_for_time(topic, time, &latest)),
+            until_time: args.until_time.map(|time| self.offsets_for_time(topic, time, &latest)),
+            earliest,
+            latest
+        }
+    }
+
+    /// Looks up the offset of the first message produced at or after `time`
+    /// for each partition, like Kafka does. Partitions without such a
+    /// message get their `latest` offset.
+    fn offsets_for_time(&self, topic: &str, time: i64, latest: &HashMap<i32, i64>) -> HashMap<i32, i64> {
+        let mut offsets = latest.clone();
+        for r in self.records.iter().filter(|r| r.topic == topic && r.timestamp.is_some_and(|t| t >= time)) {
+            let offset = offsets.get_mut(&r.partition).unwrap();
+            *offset = min(*offset, r.offset);
+        }
+        offsets
+    }
+}
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok()).collect()
+}
+
+/// Parses the bytes in the field `name` of `json`, which is UTF-8 text, or
+/// in `<name>_base64` or `<name>_hex`.
+fn parse_bytes(json: &Value, name: &str) -> ::std::result::Result<Option<Vec<u8>>, String> {
+    let base64_name = format!("{}_base64", name);
+    let hex_name = format!("{}_hex", name);
+    if let Some(s) = json[&base64_name].as_str() {
+        return STANDARD.decode(s).map(Some).map_err(|e| format!("'{}' is not valid Base64: {}", base64_name, e));
+    }
+    if let Some(s) = json[&hex_name].as_str() {
+        return parse_hex(s).map(Some).ok_or(format!("'{}' is not valid hex", hex_name));
+    }
+    match json[name] {
+        Value::Null => Ok(None),
+        Value::String(ref s) => Ok(Some(s.clone().into_bytes())),
+        _ => Err(format!("'{}' is neither a string nor null", name))
+    }
+}
+
+fn parse_record(json: &Value) -> ::std::result::Result<Record, String> {
+    let topic = json["topic"].as_str().ok_or("'topic' is missing")?;
+    let partition = json["partition"].as_i64().ok_or("'partition' is missing")?;
+    let offset = json["offset"].as_i64().ok_or("'offset' is missing")?;
+    let timestamp = json["timestamp"].as_i64();
+    let timestamp_type = match json["timestamp_type"].as_str() {
+        _ if timestamp.is_none() => None,
+        None | Some("CreateTime") => Some(TimestampType::CreateTime),
+        Some("LogAppendTime") => Some(TimestampType::LogAppendTime),
+        Some(t) => return Err(format!("'{}' is not a timestamp type", t))
+    };
+    let mut headers = vec![];
+    if let Some(list) = json["headers"].as_array() {
+        for header in list {
+            let name = header["name"].as_str().ok_or("A header has no name")?;
+            headers.push((name.to_string(), parse_bytes(header, "value")?));
+        }
+    }
+    Ok(Record {
+        topic: topic.to_string(),
+        partition: partition as i32,
+        offset,
+        key: parse_bytes(json, "key")?.unwrap_or_default(),
+        value: parse_bytes(json, "value")?.unwrap_or_default(),
+        timestamp,
+        timestamp_type,
+        headers
+    })
+}
+
+impl Source for MemorySource {
+    fn topic_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self.records.iter().map(|r| r.topic.clone()).collect();
+        names.sort();
+        names.dedup();
+        Ok(names)
+    }
+
+    /// No messages are added later, so in follow mode the topic is read up
+    /// to its last message as well.
+    fn read_topic(&self,
+                  args: &Args,
+                  topic: &str,
+                  resume: &HashMap<i32, i64>,
+                  progress: &Mutex<Progress>,
+                  tx: &SyncSender<Vec<Record>>) -> Result<()> {
+        let offsets = self.offsets(args, topic);
+        let partitions = plan_partitions(args, topic, resume, &offsets, progress)?;
+        let mut records = vec![];
+        for r in self.records.iter().filter(|r| r.topic == topic) {
+            let state = match partitions.get(&r.partition) {
+                Some(state) => state,
+                None => continue
+            };
+            if r.offset < state.position || state.end.is_some_and(|end| r.offset >= end) {
+                continue;
+            }
+            records.push(r.clone());
+            if records.len() >= BATCH_SIZE {
+                // When sending fails, the writer stopped and reports its own error.
+                if tx.send(records).is_err() {
+                    return Ok(());
+                }
+                records = vec![];
+            }
+        }
+        if !records.is_empty() {
+            let _ = tx.send(records);
+        }
+        for &partition in partitions.keys() {
+            progress.lock().unwrap().finish_partition(topic, partition);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use tempfile::TempDir;
+    use echo::{Binary, Echo, Format};
+    use source::TimestampType;
+    use tests::{query, record, run_dump};
+    use super::MemorySource;
+
+    fn from_lines(dir: &TempDir, lines: &[&str]) -> ::error::Result<MemorySource> {
+        let path = dir.path().join("fixture.jsonl");
+        let mut file = File::create(&path).unwrap();
+        for line in lines {
+            writeln!(file, "{}", line).unwrap();
+        }
+        MemorySource::from_file(path.to_str().unwrap())
+    }
+
+    #[test]
+    fn binary_fields() {
+        let dir = TempDir::new().unwrap();
+        let source = from_lines(&dir, &[
+            r#"{"topic": "orders", "partition": 0, "offset": 7, "key_hex": "00ff", "value_base64": "3q2+7w==", "headers": [{"name": "a", "value_hex": "0102"}, {"name": "b", "value": null}]}"#
+        ]).unwrap();
+        let r = &source.records[0];
+        assert_eq!(r.key, vec![0x00, 0xff]);
+        assert_eq!(r.value, vec![0xde, 0xad, 0xbe, 0xef]);
+        assert_eq!(r.headers, vec![("a".to_string(), Some(vec![1, 2])), ("b".to_string(), None)]);
+    }
+
+    #[test]
+    fn invalid_binary_fields() {
+        let dir = TempDir::new().unwrap();
+        let e = from_lines(&dir, &["", r#"{"topic": "orders", "partition": 0, "offset": 7, "key_hex": "0f0"}"#]).err().unwrap();
+        assert!(e.to_string().contains("line 2"));
+        assert!(e.to_string().contains("'key_hex' is not valid hex"));
+        assert!(from_lines(&dir, &[r#"{"topic": "orders", "partition": 0, "offset": 7, "value_base64": "!"}"#]).is_err());
+    }
+
+    #[test]
+    fn decoded_binary_values() {
+        let dir = TempDir::new().unwrap();
+        let source = from_lines(&dir, &[
+            r#"{"topic": "orders", "partition": 0, "offset": 0, "value_hex": "000000000000002a"}"#,
+            r#"{"topic": "orders", "partition": 0, "offset": 1, "value_hex": "fffffffffffffffe"}"#
+        ]).unwrap();
+        run_dump(&dir, source.records, &["--value-format", "int64-be"]).unwrap();
+        assert_eq!(query(&dir, "select value || '' from orders order by offset"), vec!["42", "-2"]);
+    }
+
+    #[test]
+    fn echo_round_trip() {
+        let dir = TempDir::new().unwrap();
+        let mut text = record(0, 0, "k", "{\"status\": \"new\"}");
+        text.timestamp_type = Some(TimestampType::LogAppendTime);
+        let mut binary = record(0, 1, "", "");
+        binary.key = vec![0xff, 0x00];
+        binary.value = vec![0xde, 0xad];
+        binary.timestamp = None;
+        binary.timestamp_type = None;
+        binary.headers = vec![
+            ("a".to_string(), Some(vec![0xff])),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(b"text".to_vec()))
+        ];
+        let records = vec![text, binary, record(0, 2, "", "")];
+        let echo = Echo::new(Format::Json, Binary::Lossy, None);
+        let lines: Vec<String> = records.iter().map(|r| echo.json(r).to_string()).collect();
+        let lines: Vec<&str> = lines.iter().map(|l| l.as_str()).collect();
+        assert_eq!(from_lines(&dir, &lines).unwrap().records, records);
+    }
+}
@@ -0,0 +1,209 @@
+//! Where the messages of a dump are read from.
+//!
+//! Usually that is a Kafka cluster. With `--from-file` the messages are
+//! read from a JSON Lines file instead, so a dump can be tried without a
+//! broker. Both read the same ranges of offsets.
+
+mod kafka;
+mod memory;
+
+use std::cmp::{min, max};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::SyncSender;
+use regex::Regex;
+use error::{Error, Result};
+use progress::Progress;
+use Args;
+
+pub use self::kafka::KafkaSource;
+pub use self::memory::MemorySource;
+
+/// The maximum number of messages passed to the writer at once.
+const BATCH_SIZE: usize = 1_000;
+
+/// Who set the timestamp of a message.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimestampType {
+    /// The producer, when it created the message.
+    CreateTime,
+    /// The broker, when it appended the message to the log.
+    LogAppendTime
+}
+
+impl TimestampType {
+    /// The name stored in the `timestamp_type` column.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            TimestampType::CreateTime => "CreateTime",
+            TimestampType::LogAppendTime => "LogAppendTime"
+        }
+    }
+}
+
+/// A message read from a source.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub topic: String,
+    pub partition: i32,
+    pub offset: i64,
+    pub key: Vec<u8>,
+    pub value: Vec<u8>,
+    /// Milliseconds since the epoch. Messages written before Kafka 0.10 have
+    /// no timestamp.
+    pub timestamp: Option<i64>,
+    pub timestamp_type: Option<TimestampType>,
+    /// The names and values of the headers in their original order.
+    pub headers: Vec<(String, Option<Vec<u8>>)>
+}
+
+/// A source of messages. Each topic is read by its own thread.
+pub trait Source: Send + Sync {
+    /// Returns the names of all topics.
+    fn topic_names(&self) -> Result<Vec<String>>;
+
+    /// Reads `topic` up to the latest offsets of its partitions at the time
+    /// this function is called and sends the messages to `tx` in batches.
+    /// Messages produced later are not read, so the dump is a consistent
+    /// snapshot of the topic. In follow mode Kafka is read forever. The
+    /// partitions and offset ranges given on the command line, as well as
+    /// `--from-time` and `--until-time`, narrow the range further. `resume`
+    /// holds the offsets to continue from, when resuming a dump.
+    fn read_topic(&self,
+                  args: &Args,
+                  topic: &str,
+                  resume: &HashMap<i32, i64>,
+                  progress: &Mutex<Progress>,
+                  tx: &SyncSender<Vec<Record>>) -> Result<()>;
+}
+
+/// Opens the source given on the command line.
+pub fn open(args: &Args) -> Result<Arc<dyn Source>> {
+    Ok(match args.from_file {
+        Some(ref path) => Arc::new(MemorySource::from_file(path)?),
+        None => Arc::new(KafkaSource::new(args))
+    })
+}
+
+/// Adds all topics matching `args.topic_pattern` to the explicitly given
+/// topics. Internal topics like `__consumer_offsets` never match.
+pub fn resolve_topics(args: &Args, source: &dyn Source) -> Result<Vec<String>> {
+    let mut topics = args.topics.clone();
+    if let Some(ref pattern) = args.topic_pattern {
+        let re = Regex::new(&format!("^(?:{})$", pattern)).map_err(|e| Error::Args(e.to_string()))?;
+        let mut matching: Vec<String> = source.topic_names()?
+            .into_iter()
+            .filter(|name| !name.starts_with("__") && re.is_match(name))
+            .collect();
+        matching.sort();
+        for name in matching {
+            if !topics.contains(&name) {
+                topics.push(name);
+            }
+        }
+    }
+    Ok(topics)
+}
+
+/// The offsets of the partitions of a topic, as far as they matter for the
+/// range which is read.
+struct TopicOffsets {
+    /// The first offset of each partition.
+    earliest: HashMap<i32, i64>,
+    /// The offset after the last message of each partition.
+    latest: HashMap<i32, i64>,
+    /// The offsets of the first messages produced at or after `--from-time`.
+    from_time: Option<HashMap<i32, i64>>,
+    /// The offsets of the first messages produced at or after
+    /// `--until-time`.
+    until_time: Option<HashMap<i32, i64>>
+}
+
+/// The read position of a single partition.
+struct PartitionState {
+    /// The next offset to read.
+    position: i64,
+    /// The offset after the last message to read. `None` in follow mode.
+    end: Option<i64>
+}
+
+impl PartitionState {
+    fn done(&self) -> bool {
+        self.end.is_some_and(|end| self.position >= end)
+    }
+}
+
+/// Decides which offsets of each partition of `topic` are read and
+/// registers the partitions with `progress`.
+fn plan_partitions(args: &Args,
+                   topic: &str,
+                   resume: &HashMap<i32, i64>,
+                   offsets: &TopicOffsets,
+                   progress: &Mutex<Progress>) -> Result<HashMap<i32, PartitionState>> {
+    let selected: Vec<i32> = if args.partitions.is_empty() {
+        offsets.latest.keys().cloned().collect()
+    } else {
+        args.partitions.iter().map(|p| p.partition).collect()
+    };
+    let mut partitions = HashMap::new();
+    for partition in selected {
+        let latest_offset = match offsets.latest.get(&partition) {
+            Some(&offset) => offset,
+            None => return Err(Error::Args(format!("Topic {} has no partition {}", topic, partition)))
+        };
+        let earliest_offset = offsets.earliest[&partition];
+        let spec = args.partitions.iter().find(|p| p.partition == partition);
+        let requested = spec.and_then(|s| s.start)
+            .or(args.start_offset)
+            .or_else(|| offsets.from_time.as_ref().map(|offsets| offsets[&partition]));
+        // A resumed dump continues after the stored messages, unless the
+        // requested start is later. `None` is less than any offset.
+        let start = max(requested, resume.get(&partition).cloned())
+            .map_or(earliest_offset, |offset| max(offset, earliest_offset));
+        let end = spec.and_then(|s| s.end)
+            .or(args.end_offset)
+            .map(|offset| offset + 1)
+            .or_else(|| offsets.until_time.as_ref().map(|offsets| offsets[&partition]))
+            .or(if args.follow { None } else { Some(latest_offset) })
+            .map(|offset| if args.follow { offset } else { min(offset, latest_offset) });
+        progress.lock().unwrap().start_partition(topic, partition, start, end);
+        partitions.insert(partition, PartitionState {
+            position: start,
+            end
+        });
+    }
+    Ok(partitions)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Mutex;
+    use std::sync::mpsc::sync_channel;
+    use progress::Progress;
+    use tests::record;
+    use parse_args;
+    use super::{MemorySource, Record, Source, resolve_topics};
+
+    #[test]
+    fn read_range() {
+        let mut records: Vec<Record> = (0..10).map(|o| record(0, o, "k", "v")).collect();
+        records.extend((5..10).map(|o| record(1, o, "k", "v")));
+        let args = parse_args(vec!["dump-kafka-to-sql", "-t", "orders", "--partition", "0:2-4"]);
+        let (tx, rx) = sync_channel(10);
+        let progress = Mutex::new(Progress::new(false));
+        MemorySource::new(records).read_topic(&args, "orders", &HashMap::new(), &progress, &tx).unwrap();
+        drop(tx);
+        let offsets: Vec<(i32, i64)> = rx.iter().flatten().map(|r| (r.partition, r.offset)).collect();
+        assert_eq!(offsets, vec![(0, 2), (0, 3), (0, 4)]);
+    }
+
+    #[test]
+    fn topic_pattern() {
+        let records: Vec<Record> = ["__consumer_offsets", "orders", "orders_archive", "payments", "xorders"].iter()
+            .map(|topic| Record { topic: topic.to_string(), ..record(0, 0, "k", "v") })
+            .collect();
+        let args = parse_args(vec!["dump-kafka-to-sql", "-t", "payments", "--topic-pattern", "orders|payments|__.*"]);
+        assert_eq!(resolve_topics(&args, &MemorySource::new(records)).unwrap(), vec!["payments", "orders"]);
+    }
+}
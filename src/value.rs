@@ -0,0 +1,36 @@
+//! Values written to the database.
+
+use rusqlite::types::{Null, ToSql};
+
+/// The type of a column. Each sink maps it to a type of its database.
+#[derive(Clone, Copy, PartialEq)]
+pub enum SqlType {
+    Integer,
+    Real,
+    Text,
+    Blob,
+    /// JSON text. Databases without a JSON type store it as text.
+    Json
+}
+
+/// An owned value of one of the SQLite storage classes.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlValue {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>)
+}
+
+impl SqlValue {
+    pub fn as_sql(&self) -> &dyn ToSql {
+        match *self {
+            SqlValue::Null => &Null,
+            SqlValue::Integer(ref i) => i,
+            SqlValue::Real(ref r) => r,
+            SqlValue::Text(ref s) => s,
+            SqlValue::Blob(ref b) => b
+        }
+    }
+}